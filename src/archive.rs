@@ -0,0 +1,50 @@
+//! Reading of `.crate` and `.tar.gz` archives, which back archive roots.
+use std::io::{self, Read};
+
+use flate2::read::GzDecoder;
+use relative_path::RelativePathBuf;
+
+/// Reads all regular files of a gzipped tar archive.
+///
+/// If all files are inside a single top-level directory (like `serde-1.0.0/`
+/// in `serde-1.0.0.crate`), it is stripped from the paths.
+pub(crate) fn read_archive(bytes: &[u8]) -> io::Result<Vec<(RelativePathBuf, Vec<u8>)>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = match RelativePathBuf::from_path(entry.path()?) {
+            Ok(it) => it.normalize(),
+            Err(e) => {
+                log::warn!("skipping archive entry: {}", e);
+                continue;
+            }
+        };
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.push((path, contents));
+    }
+
+    let top_level_dir = files.first().and_then(|(path, _)| {
+        let dir = path.components().next()?.as_str().to_string();
+        let is_common = files.iter().all(|(path, _)| {
+            let mut components = path.components();
+            components.next().map(|it| it.as_str()) == Some(dir.as_str())
+                && components.next().is_some()
+        });
+        if is_common {
+            Some(dir)
+        } else {
+            None
+        }
+    });
+    if let Some(dir) = top_level_dir {
+        for (path, _) in files.iter_mut() {
+            *path = path.strip_prefix(&dir).unwrap().to_relative_path_buf();
+        }
+    }
+    Ok(files)
+}
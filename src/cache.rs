@@ -0,0 +1,228 @@
+//! Persistent cache of the roots' contents, which makes startup faster.
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use relative_path::RelativePathBuf;
+use rustc_hash::FxHashMap;
+
+use crate::{LineEndings, Metadata};
+
+/// Identifies the format of the cache file. Bump the version whenever the
+/// format changes.
+const MAGIC: &[u8; 8] = b"RAVFS002";
+
+/// Contents of the roots, saved to disk between runs.
+///
+/// A cache is created with `Vfs::cache`, saved with `write` and passed to
+/// `Vfs::with_cache` on the next start. It stores paths, sizes, modification
+/// times and content hashes of files, and, for archive roots, which are
+/// immutable, their texts too. Roots are served from the cache immediately,
+/// and checked for modifications in the background: the archive of an
+/// archive root is compared with its cached metadata, while the files of a
+/// disk root are compared with theirs, and, if the metadata differs, with
+/// their cached hashes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VfsCache {
+    roots: FxHashMap<PathBuf, CachedRoot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CachedRoot {
+    /// Metadata of the archive itself, for archive roots.
+    pub(crate) archive: Option<Metadata>,
+    pub(crate) files: Vec<CachedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CachedFile {
+    pub(crate) path: RelativePathBuf,
+    pub(crate) metadata: Metadata,
+    pub(crate) hash: u64,
+    pub(crate) line_endings: LineEndings,
+    /// Texts are only cached for archive roots.
+    pub(crate) text: Option<String>,
+}
+
+impl VfsCache {
+    /// Reads a cache previously saved with `write`.
+    pub fn read(path: &Path) -> io::Result<VfsCache> {
+        let bytes = fs::read(path)?;
+        let mut reader = Reader::new(&bytes, "VFS cache");
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("not a VFS cache, or an unsupported version"));
+        }
+        let mut roots = FxHashMap::default();
+        for _ in 0..reader.u64()? {
+            let path = PathBuf::from(reader.str()?);
+            let archive = if reader.u8()? != 0 { Some(reader.metadata()?) } else { None };
+            let n_files = reader.u64()?;
+            let mut files = Vec::new();
+            for _ in 0..n_files {
+                let path = RelativePathBuf::from(reader.str()?);
+                let metadata = reader.metadata()?;
+                let hash = reader.u64()?;
+                let line_endings =
+                    if reader.u8()? == 0 { LineEndings::Unix } else { LineEndings::Dos };
+                let text = if reader.u8()? != 0 { Some(reader.str()?) } else { None };
+                files.push(CachedFile { path, metadata, hash, line_endings, text });
+            }
+            roots.insert(path, CachedRoot { archive, files });
+        }
+        Ok(VfsCache { roots })
+    }
+
+    /// Saves the cache to `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        write_u64(&mut buf, self.roots.len() as u64);
+        for (path, root) in self.roots.iter() {
+            let path = path.to_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("path of a cached root is not UTF-8: {}", path.display()),
+                )
+            })?;
+            write_str(&mut buf, path);
+            match root.archive {
+                Some(metadata) => {
+                    buf.push(1);
+                    write_metadata(&mut buf, metadata);
+                }
+                None => buf.push(0),
+            }
+            write_u64(&mut buf, root.files.len() as u64);
+            for file in root.files.iter() {
+                write_str(&mut buf, file.path.as_str());
+                write_metadata(&mut buf, file.metadata);
+                write_u64(&mut buf, file.hash);
+                buf.push(match file.line_endings {
+                    LineEndings::Unix => 0,
+                    LineEndings::Dos => 1,
+                });
+                match &file.text {
+                    None => buf.push(0),
+                    Some(text) => {
+                        buf.push(1);
+                        write_str(&mut buf, text);
+                    }
+                }
+            }
+        }
+        fs::File::create(path)?.write_all(&buf)
+    }
+
+    /// Returns `true` if the cache has no roots.
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, path: PathBuf, root: CachedRoot) {
+        self.roots.insert(path, root);
+    }
+
+    pub(crate) fn take(&mut self, path: &Path) -> Option<CachedRoot> {
+        self.roots.remove(path)
+    }
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub(crate) fn write_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_str(buf: &mut Vec<u8>, value: &str) {
+    write_bytes(buf, value.as_bytes());
+}
+
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    write_u64(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+pub(crate) fn write_metadata(buf: &mut Vec<u8>, metadata: Metadata) {
+    write_u64(buf, metadata.len);
+    match metadata.modified.and_then(|it| it.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+        Some(modified) => {
+            buf.push(1);
+            write_u64(buf, modified.as_secs());
+            write_u64(buf, u64::from(modified.subsec_nanos()));
+        }
+        None => buf.push(0),
+    }
+}
+
+/// Reads the values written by the `write_*` functions.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    /// What is being read, for error messages.
+    what: &'static str,
+    /// Set once a read ran past the end of `bytes`.
+    truncated: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], what: &'static str) -> Reader<'a> {
+        Reader { bytes, what, truncated: false }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            self.truncated = true;
+            return Err(invalid_data(&format!("unexpected end of the {}", self.what)));
+        }
+        let (res, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(res)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn str(&mut self) -> io::Result<String> {
+        let bytes = self.byte_string()?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid_data(&e.to_string()))
+    }
+
+    pub(crate) fn byte_string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.bytes(len.try_into().map_err(|_| invalid_data("string is too long"))?)
+    }
+
+    pub(crate) fn metadata(&mut self) -> io::Result<Metadata> {
+        let len = self.u64()?;
+        let modified = if self.u8()? != 0 { Some(self.time()?) } else { None };
+        Ok(Metadata { is_dir: false, len, modified })
+    }
+
+    /// Reads a time written as seconds and nanoseconds since the Unix epoch.
+    pub(crate) fn time(&mut self) -> io::Result<SystemTime> {
+        let secs = self.u64()?;
+        let nanos: Option<u32> = self.u64()?.try_into().ok();
+        nanos
+            .filter(|&nanos| nanos < 1_000_000_000)
+            .and_then(|nanos| SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+            .ok_or_else(|| invalid_data("invalid time"))
+    }
+}
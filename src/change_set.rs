@@ -0,0 +1,182 @@
+//! Changes returned by `Vfs::commit_changes`, grouped by root.
+use std::{
+    ops::{Index, Range},
+    slice, vec,
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{VfsChange, VfsFile, VfsRoot};
+
+/// The changes of the VFS since the previous `Vfs::commit_changes`.
+///
+/// Besides the raw changes, in the order they happened, a change set groups
+/// the touched files by root and by kind of change.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeSet {
+    changes: Vec<VfsChange>,
+    revisions: Range<u64>,
+    roots: FxHashMap<VfsRoot, RootChanges>,
+    added_roots: Vec<VfsRoot>,
+    touched: FxHashSet<VfsFile>,
+}
+
+/// The files of a root touched by a `ChangeSet`.
+///
+/// A file is listed at most once per kind of change, but may be listed under
+/// several kinds, for example if it was added and then changed.
+#[derive(Debug, Clone, Default)]
+pub struct RootChanges {
+    added: Vec<VfsFile>,
+    removed: Vec<VfsFile>,
+    changed: Vec<VfsFile>,
+}
+
+impl RootChanges {
+    /// Files which were added, including the files of new roots and chunks.
+    pub fn added(&self) -> &[VfsFile] {
+        &self.added
+    }
+
+    pub fn removed(&self) -> &[VfsFile] {
+        &self.removed
+    }
+
+    pub fn changed(&self) -> &[VfsFile] {
+        &self.changed
+    }
+}
+
+impl ChangeSet {
+    /// Groups `changes`, which happened after `revisions.start`, up to
+    /// `revisions.end`. `root_of` returns the root of a changed file.
+    pub(crate) fn new(
+        changes: Vec<VfsChange>,
+        revisions: Range<u64>,
+        root_of: impl Fn(VfsFile) -> VfsRoot,
+    ) -> ChangeSet {
+        let mut roots = FxHashMap::<VfsRoot, RootChanges>::default();
+        let mut added_roots = Vec::new();
+        let (mut added, mut removed, mut changed) =
+            (FxHashSet::default(), FxHashSet::default(), FxHashSet::default());
+        for change in changes.iter() {
+            match change {
+                VfsChange::AddRoot { root, files, unloaded, .. }
+                | VfsChange::AddRootChunk { root, files, unloaded, .. } => {
+                    if let VfsChange::AddRoot { .. } = change {
+                        added_roots.push(*root);
+                    }
+                    let group = roots.entry(*root).or_default();
+                    let files = files.iter().map(|it| it.0).chain(unloaded.iter().map(|it| it.0));
+                    for file in files {
+                        push_new(&mut group.added, &mut added, file);
+                    }
+                }
+                VfsChange::AddFile { root, file, .. } => {
+                    push_new(&mut roots.entry(*root).or_default().added, &mut added, *file);
+                }
+                VfsChange::RemoveFile { root, file, .. } => {
+                    push_new(&mut roots.entry(*root).or_default().removed, &mut removed, *file);
+                }
+                VfsChange::ChangeFile { file, .. } => {
+                    let group = roots.entry(root_of(*file)).or_default();
+                    push_new(&mut group.changed, &mut changed, *file);
+                }
+            }
+        }
+        let touched = added.into_iter().chain(removed).chain(changed).collect();
+        ChangeSet { changes, revisions, roots, added_roots, touched }
+    }
+
+    /// Number of raw changes.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[VfsChange] {
+        &self.changes
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, VfsChange> {
+        self.changes.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<VfsChange> {
+        self.changes
+    }
+
+    /// The revisions covered by the changes: the VFS was at `start` when the
+    /// previous change set was committed, and is at `end` now.
+    pub fn revisions(&self) -> Range<u64> {
+        self.revisions.clone()
+    }
+
+    /// Returns `true` if `file` was added, removed or changed.
+    pub fn touches(&self, file: VfsFile) -> bool {
+        self.touched.contains(&file)
+    }
+
+    /// Roots with touched files, or which were added.
+    pub fn roots(&self) -> impl Iterator<Item = VfsRoot> + '_ {
+        self.roots.keys().copied()
+    }
+
+    /// Returns the files of `root` touched by the changes, if any.
+    pub fn root(&self, root: VfsRoot) -> Option<&RootChanges> {
+        self.roots.get(&root)
+    }
+
+    /// Roots whose initial load finished, that is, which were reported with
+    /// `AddRoot`.
+    pub fn added_roots(&self) -> &[VfsRoot] {
+        &self.added_roots
+    }
+
+    pub fn n_added(&self) -> usize {
+        self.roots.values().map(|it| it.added.len()).sum()
+    }
+
+    pub fn n_removed(&self) -> usize {
+        self.roots.values().map(|it| it.removed.len()).sum()
+    }
+
+    pub fn n_changed(&self) -> usize {
+        self.roots.values().map(|it| it.changed.len()).sum()
+    }
+}
+
+fn push_new(files: &mut Vec<VfsFile>, seen: &mut FxHashSet<VfsFile>, file: VfsFile) {
+    if seen.insert(file) {
+        files.push(file);
+    }
+}
+
+impl IntoIterator for ChangeSet {
+    type Item = VfsChange;
+    type IntoIter = vec::IntoIter<VfsChange>;
+
+    fn into_iter(self) -> vec::IntoIter<VfsChange> {
+        self.changes.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChangeSet {
+    type Item = &'a VfsChange;
+    type IntoIter = slice::Iter<'a, VfsChange>;
+
+    fn into_iter(self) -> slice::Iter<'a, VfsChange> {
+        self.changes.iter()
+    }
+}
+
+impl Index<usize> for ChangeSet {
+    type Output = VfsChange;
+
+    fn index(&self, index: usize) -> &VfsChange {
+        &self.changes[index]
+    }
+}
@@ -0,0 +1,237 @@
+//! Abstraction over the file system, which the VFS reads files from.
+//!
+//! `OsFileSystem` is the real file system, `MemoryFileSystem` keeps everything
+//! in memory, which is handy for tests.
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use parking_lot::RwLock;
+use walkdir::WalkDir;
+
+/// Metadata of a file or a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Size of the file in bytes.
+    pub len: u64,
+    /// Last modification time, if known.
+    pub modified: Option<SystemTime>,
+}
+
+/// Operations the VFS needs from the file system.
+///
+/// All paths are absolute.
+pub trait FileSystem: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        let bytes = self.read(path)?;
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Recursively lists `dir`, including `dir` itself.
+    ///
+    /// `include` is called for each entry: if it returns `false`, the entry is
+    /// skipped, and, for directories, their contents are not listed.
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)>;
+}
+
+/// The file system of the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(|it| to_metadata(&it))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)> {
+        let mut res = Vec::new();
+        // FIXME: this is broken for symlinks at the moment
+        let mut iter = WalkDir::new(dir).into_iter();
+        while let Some(entry) = iter.next() {
+            let entry = match entry {
+                Ok(it) => it,
+                Err(e) => {
+                    log::warn!("watcher error: {}", e);
+                    continue;
+                }
+            };
+            let metadata = match entry.metadata() {
+                Ok(it) => to_metadata(&it),
+                Err(e) => {
+                    log::warn!("watcher error: {}", e);
+                    continue;
+                }
+            };
+            if !include(entry.path(), &metadata) {
+                if metadata.is_dir {
+                    iter.skip_current_dir();
+                }
+                continue;
+            }
+            res.push((entry.into_path(), metadata));
+        }
+        res
+    }
+}
+
+fn to_metadata(metadata: &fs::Metadata) -> Metadata {
+    Metadata { is_dir: metadata.is_dir(), len: metadata.len(), modified: metadata.modified().ok() }
+}
+
+/// A file system which keeps all files in memory.
+///
+/// Directories are implicit: a directory exists if it contains a file.
+/// Modification times are logical: each write advances a counter.
+#[derive(Debug, Default)]
+pub struct MemoryFileSystem {
+    inner: RwLock<MemoryInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    files: BTreeMap<PathBuf, (Vec<u8>, SystemTime)>,
+    generation: u64,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Creates or overwrites the file at `path`.
+    pub fn write(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        let mut inner = self.inner.write();
+        inner.generation += 1;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(inner.generation);
+        inner.files.insert(path.into(), (contents.into(), modified));
+    }
+
+    /// Removes the file at `path`, returning `true` if it existed.
+    pub fn remove(&self, path: &Path) -> bool {
+        self.inner.write().files.remove(path).is_some()
+    }
+}
+
+impl MemoryInner {
+    fn is_dir(&self, path: &Path) -> bool {
+        match self.files.range(path.to_path_buf()..).next() {
+            Some((it, _)) => it.starts_with(path) && it != path,
+            None => false,
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let inner = self.inner.read();
+        inner.files.get(path).map(|(contents, _)| contents.clone()).ok_or_else(|| not_found(path))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let inner = self.inner.read();
+        if let Some((contents, modified)) = inner.files.get(path) {
+            return Ok(Metadata {
+                is_dir: false,
+                len: contents.len() as u64,
+                modified: Some(*modified),
+            });
+        }
+        if inner.is_dir(path) {
+            return Ok(Metadata { is_dir: true, len: 0, modified: None });
+        }
+        Err(not_found(path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.metadata(path)?;
+        Ok(path.to_path_buf())
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)> {
+        let inner = self.inner.read();
+        let mut res = Vec::new();
+        if !inner.is_dir(dir) {
+            return res;
+        }
+        let dir_metadata = Metadata { is_dir: true, len: 0, modified: None };
+        if !include(dir, &dir_metadata) {
+            return res;
+        }
+        res.push((dir.to_path_buf(), dir_metadata));
+
+        // Directories which were already listed or skipped.
+        let mut dirs = BTreeMap::new();
+        for (path, (contents, modified)) in inner.files.range(dir.to_path_buf()..) {
+            if !path.starts_with(dir) {
+                break;
+            }
+            let mut included = true;
+            let ancestors =
+                path.ancestors().skip(1).take_while(|it| *it != dir).collect::<Vec<_>>();
+            // Visit directories top-down, so that skipped directories prune
+            // their contents.
+            for ancestor in ancestors.into_iter().rev() {
+                let ancestor = ancestor.to_path_buf();
+                if !dirs.contains_key(&ancestor) {
+                    let metadata = Metadata { is_dir: true, len: 0, modified: None };
+                    let is_included = include(&ancestor, &metadata);
+                    dirs.insert(ancestor.clone(), is_included);
+                    if is_included {
+                        res.push((ancestor.clone(), metadata));
+                    }
+                }
+                if !dirs[&ancestor] {
+                    included = false;
+                    break;
+                }
+            }
+            if !included {
+                continue;
+            }
+            let metadata =
+                Metadata { is_dir: false, len: contents.len() as u64, modified: Some(*modified) };
+            if include(path, &metadata) {
+                res.push((path.clone(), metadata));
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,81 @@
+//! Hashing of file contents.
+//!
+//! Hashes are used as fingerprints which are persisted across runs, so they
+//! must not depend on the platform or on a random seed.
+use relative_path::RelativePath;
+
+/// A 64-bit FNV-1a hasher.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub(crate) fn hash_text(text: &str) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(text.as_bytes());
+    hasher.finish()
+}
+
+/// Aggregate hash of the paths and hashes of the files of a root, which
+/// changes if any file is added, removed or changed.
+///
+/// The hashes of the files are summed, so that the aggregate doesn't depend on
+/// their order, and is updated in constant time as files change.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RootHash {
+    sum: u64,
+    n_files: u64,
+    /// Number of files which were not loaded yet, and have no hash.
+    n_unhashed: u64,
+}
+
+impl RootHash {
+    pub(crate) fn insert(&mut self, path: &RelativePath, hash: Option<u64>) {
+        self.n_files += 1;
+        match hash {
+            Some(hash) => self.sum = self.sum.wrapping_add(hash_file(path, hash)),
+            None => self.n_unhashed += 1,
+        }
+    }
+
+    pub(crate) fn remove(&mut self, path: &RelativePath, hash: Option<u64>) {
+        self.n_files -= 1;
+        match hash {
+            Some(hash) => self.sum = self.sum.wrapping_sub(hash_file(path, hash)),
+            None => self.n_unhashed -= 1,
+        }
+    }
+
+    /// Returns the hash, or `None` if some of the files have no hash.
+    pub(crate) fn finish(&self) -> Option<u64> {
+        if self.n_unhashed != 0 {
+            return None;
+        }
+        let mut hasher = StableHasher::new();
+        hasher.write(&self.n_files.to_le_bytes());
+        hasher.write(&self.sum.to_le_bytes());
+        Some(hasher.finish())
+    }
+}
+
+fn hash_file(path: &RelativePath, hash: u64) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(path.as_str().as_bytes());
+    // Separates the path from the hash, as paths are of variable length.
+    hasher.write(&[0]);
+    hasher.write(&hash.to_le_bytes());
+    hasher.finish()
+}
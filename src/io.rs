@@ -1,27 +1,76 @@
 use std::{
+    collections::VecDeque,
+    mem,
     path::{Path, PathBuf},
-    sync::{mpsc, Arc},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, SystemTime},
 };
-use crossbeam_channel::{Sender, unbounded, RecvError, select};
+use crossbeam_channel::{Sender, bounded, never, unbounded, RecvError, TrySendError, select};
+use parking_lot::Mutex;
 use relative_path::RelativePathBuf;
-use walkdir::WalkDir;
+use rustc_hash::FxHashMap;
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher as _Watcher};
 
-use crate::{Roots, VfsRoot, VfsTask, roots::FileType, LineEndings, read_to_string, Watch};
+use crate::{
+    ChangeOrigin, Roots, VfsRoot, VfsTask, VfsProgress,
+    roots::{FileType, RootKind},
+    archive, FileContents, FileSystem, Metadata, read_to_string, Watch,
+    memory::TextInterner,
+};
 
 pub(crate) enum Task {
-    AddRoot { root: VfsRoot },
-    NotifyChanged { path: PathBuf },
+    AddRoot {
+        root: VfsRoot,
+    },
+    /// Replaces the set of roots after a root has been added.
+    UpdateRoots {
+        roots: Arc<Roots>,
+    },
+    NotifyChanged {
+        path: PathBuf,
+        origin: ChangeOrigin,
+    },
+    /// Moves a root which is waiting to be loaded to the front of the queue.
+    Prioritize {
+        root: VfsRoot,
+    },
+    /// Stops loading a root: it is reported with the files read so far.
+    CancelLoad {
+        root: VfsRoot,
+    },
+    /// Starts watching an archive root which was served from the cache, and
+    /// reloads it if the archive differs from the cached `metadata`.
+    ValidateArchive {
+        root: VfsRoot,
+        metadata: Metadata,
+    },
+    /// Starts watching a disk root which was served from the cache, and
+    /// reports the files which differ from the cached ones.
+    ValidateRoot {
+        root: VfsRoot,
+        files: Vec<(RelativePathBuf, Metadata, u64)>,
+    },
+    /// Replied to once the changes received so far, including the ones from
+    /// the watcher, are handled, and no root is loading.
+    #[cfg(feature = "test-support")]
+    Barrier {
+        done: Sender<()>,
+    },
 }
 
 /// `TaskResult` transfers files read on the IO thread to the VFS on the main
 /// thread.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum TaskResult {
+    /// Emitted during the initial load of a root, for every chunk of files
+    /// but the last one. Contents are `None` for the files of lazy roots.
+    LoadRootChunk { root: VfsRoot, files: Vec<(RelativePathBuf, Option<FileContents>)> },
     /// Emitted when we've recursively scanned a source root during the initial
-    /// load.
-    BulkLoadRoot { root: VfsRoot, files: Vec<(RelativePathBuf, String, LineEndings)> },
+    /// load, with the last chunk of files.
+    BulkLoadRoot { root: VfsRoot, files: Vec<(RelativePathBuf, Option<FileContents>)> },
     /// Emitted when we've noticed that a single file has changed.
     ///
     /// Note that this by design does not distinguish between
@@ -32,9 +81,22 @@ pub(crate) enum TaskResult {
     SingleFile {
         root: VfsRoot,
         path: RelativePathBuf,
-        text: Option<String>,
-        line_endings: LineEndings,
+        contents: Option<FileContents>,
+        origin: ChangeOrigin,
+        /// When the change was noticed, reported as the time of the change.
+        time: SystemTime,
+    },
+    /// Emitted when a root that was already loaded has to be read anew (for
+    /// example, because its archive has changed). Unlike `BulkLoadRoot`, the
+    /// files replace the current contents of the root.
+    ReloadRoot {
+        root: VfsRoot,
+        files: Vec<(RelativePathBuf, FileContents)>,
+        origin: ChangeOrigin,
+        time: SystemTime,
     },
+    /// Emitted while a root is being loaded, before its `BulkLoadRoot`.
+    Progress(VfsProgress),
 }
 
 /// The kind of raw notification we've received from the notify library.
@@ -42,14 +104,123 @@ pub(crate) enum TaskResult {
 /// Note that these are not necessary 100% precise (for example we might receive
 /// `Create` instead of `Write`, see #734), but we try do distinguish `Create`s
 /// to implement recursive watching of directories.
-#[derive(Debug)]
-enum ChangeKind {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
     Create,
     Write,
     Remove,
 }
 
-const WATCHER_DELAY: Duration = Duration::from_millis(250);
+pub(crate) const WATCHER_DELAY: Duration = Duration::from_millis(250);
+
+/// Number of threads which load roots in parallel. Loading is mostly bound by
+/// IO, so this doesn't depend on the number of CPUs.
+const N_LOADERS: usize = 8;
+
+/// Progress of reading a root is reported every `PROGRESS_STEP` files.
+const PROGRESS_STEP: usize = 100;
+
+/// Bounds on the size of the chunks a root is loaded in.
+const CHUNK_FILES: usize = 1000;
+const CHUNK_BYTES: usize = 16 * 1024 * 1024;
+
+/// The watcher is shared between the `vfs` thread and the loaders, which
+/// watch the directories of the roots they load.
+type SharedWatcher = Arc<Mutex<RecommendedWatcher>>;
+
+/// Sends changes to the `vfs` thread as if they came from the watcher, while
+/// the thread runs. Used by `test_support::FakeWatcher`.
+#[cfg(feature = "test-support")]
+pub(crate) type ChangeSlot = Arc<Mutex<Option<Sender<(PathBuf, ChangeKind)>>>>;
+
+/// Request to a loader thread to load a root.
+struct LoadRoot {
+    roots: Arc<Roots>,
+    root: VfsRoot,
+    priority: i32,
+    cancel: CancelToken,
+    /// Paths, metadata and hashes of the cached files, if the root was
+    /// served from the cache and only has to be validated.
+    cached: Option<Vec<(RelativePathBuf, Metadata, u64)>>,
+}
+
+/// What the loaders send to the `vfs` thread.
+enum Loaded {
+    Result(TaskResult),
+    /// A root served from the cache has been validated.
+    Validated(VfsRoot),
+}
+
+/// Checked by the loaders while walking directories and reading files, so
+/// that cancelled loads stop promptly.
+#[derive(Clone, Default)]
+struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Roots waiting to be loaded, in the order of loading.
+///
+/// The queue is shared between the `vfs` thread and the loaders, so that the
+/// roots can be reordered while they wait. A loader is woken up by a message
+/// on the load channel for each root pushed into the queue.
+#[derive(Default)]
+struct LoadQueue {
+    roots: Mutex<Vec<LoadRoot>>,
+}
+
+impl LoadQueue {
+    fn push(&self, load: LoadRoot) {
+        let mut roots = self.roots.lock();
+        let idx = roots.iter().position(|it| it.priority < load.priority).unwrap_or(roots.len());
+        roots.insert(idx, load);
+    }
+
+    fn pop(&self) -> Option<LoadRoot> {
+        let mut roots = self.roots.lock();
+        if roots.is_empty() {
+            return None;
+        }
+        Some(roots.remove(0))
+    }
+
+    fn remove(&self, root: VfsRoot) -> bool {
+        let mut roots = self.roots.lock();
+        let len = roots.len();
+        roots.retain(|it| it.root != root);
+        roots.len() != len
+    }
+
+    fn prioritize(&self, root: VfsRoot) {
+        let mut roots = self.roots.lock();
+        if let Some(idx) = roots.iter().position(|it| it.root == root) {
+            let load = roots.remove(idx);
+            roots.insert(0, load);
+        }
+    }
+}
+
+/// A change which was received while the root it belongs to was still
+/// loading.
+enum HeldChange {
+    Watcher(PathBuf, ChangeKind),
+    Notify(PathBuf, ChangeOrigin),
+}
+
+impl HeldChange {
+    fn path(&self) -> &Path {
+        match self {
+            HeldChange::Watcher(path, _) | HeldChange::Notify(path, _) => path,
+        }
+    }
+}
 
 pub(crate) struct Worker {
     // XXX: field order is significant here.
@@ -61,12 +232,19 @@ pub(crate) struct Worker {
     // panic.
     sender: Sender<Task>,
     _thread: jod_thread::JoinHandle<()>,
+    #[cfg(feature = "test-support")]
+    changes: ChangeSlot,
 }
 
 impl Worker {
     pub(crate) fn send(&self, task: Task) {
         self.sender.send(task).unwrap()
     }
+
+    #[cfg(feature = "test-support")]
+    pub(crate) fn changes(&self) -> ChangeSlot {
+        Arc::clone(&self.changes)
+    }
 }
 
 fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> jod_thread::JoinHandle<()> {
@@ -74,40 +252,54 @@ fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> jod_thread::JoinHandl
 }
 
 pub(crate) fn start(
-    roots: Arc<Roots>,
-    mut output_sender: Box<dyn FnMut(VfsTask) + Send>,
+    mut roots: Arc<Roots>,
+    fs: Arc<dyn FileSystem>,
+    output: Output,
     watch: Watch,
 ) -> Worker {
     // This is a pretty elaborate setup of threads & channels! It is
     // explained by the following concerns:
     //    * we need to burn a thread translating from notify's mpsc to
     //      crossbeam_channel.
-    //    * we want to read all files from a single thread, to guarantee that
-    //      we always get fresher versions and never go back in time.
+    //    * we want to load roots in parallel, but guarantee that we always
+    //      get fresher versions of files and never go back in time. So the
+    //      results of loaders are emitted by the `vfs` thread, and the changes
+    //      of a root which is still loading are held until its `BulkLoadRoot`
+    //      has been emitted. As loaders watch directories before reading the
+    //      files, such changes are never missed.
+    //    * with a bounded output, we don't want to buffer arbitrarily many
+    //      results if the consumer falls behind. So we stop taking results
+    //      from the loaders, and coalesce changes of the same file.
     //    * we want to tear down everything neatly during shutdown.
     let _thread: jod_thread::JoinHandle<()>;
     // This are the channels we use to communicate with outside world.
     // If `input_receiver` is closed we need to tear ourselves down.
-    // `output_sender` should not be closed unless the parent died.
+    // `output` should not be closed unless the parent died.
     let (input_sender, input_receiver) = unbounded();
+    // These are the crossbeam channels for the changes from the watcher.
+    let (watcher_sender, watcher_receiver) = unbounded();
+    #[cfg(feature = "test-support")]
+    let changes = Arc::new(Mutex::new(Some(watcher_sender.clone())));
+    #[cfg(feature = "test-support")]
+    let thread_changes = Arc::clone(&changes);
 
     _thread = spawn("vfs", move || {
         // Make sure that the destruction order is
         //
+        // * loaded_receiver
+        // * load_sender
+        // * _loaders
         // * notify_sender
         // * _thread
         // * watcher_sender
         //
         // this is required to avoid deadlocks.
-
-        // These are the corresponding crossbeam channels
-        let (watcher_sender, watcher_receiver) = unbounded();
         let _notify_thread;
         {
             // These are `std` channels notify will send events to
             let (notify_sender, notify_receiver) = mpsc::channel();
 
-            let mut watcher = if watch.0 {
+            let watcher = if watch.0 {
                 match notify::watcher(notify_sender, WATCHER_DELAY) {
                     Ok(watcher) => {
                         // Start a silly thread to transform between two channels
@@ -116,7 +308,7 @@ pub(crate) fn start(
                                 .into_iter()
                                 .for_each(|event| convert_notify_event(event, &watcher_sender))
                         });
-                        Ok(watcher)
+                        Ok(Arc::new(Mutex::new(watcher)))
                     }
                     Err(e) => {
                         log::error!("failed to spawn notify {}", e);
@@ -127,61 +319,528 @@ pub(crate) fn start(
                 Err(watcher_sender)
             };
 
+            let (loaded_sender, loaded_receiver) = bounded(N_LOADERS);
+            let queue = Arc::new(LoadQueue::default());
+            let (_loaders, load_sender) = start_loaders(
+                watcher.as_ref().ok().cloned(),
+                Arc::clone(&fs),
+                Arc::clone(&queue),
+                loaded_sender,
+            );
+            // Roots which are being loaded, and changes held until they are.
+            let mut loading = FxHashMap::default();
+            let mut held: Vec<HeldChange> = Vec::new();
+            let watcher = watcher.as_ref().ok().map(|it| &**it);
+            let mut output = Delivery {
+                output,
+                backlog: VecDeque::new(),
+                n_popped: 0,
+                pending_files: FxHashMap::default(),
+            };
+            // Never ready, to disable the `send` operation below.
+            let (idle_sender, _idle_receiver) = bounded(0);
+            let never_loaded = never();
+            #[cfg(feature = "test-support")]
+            let mut barriers: Vec<Sender<()>> = Vec::new();
+
             // Process requests from the called or notifications from
             // watcher until the caller says stop.
             loop {
-                select! {
+                #[cfg(feature = "test-support")]
+                if loading.is_empty() && output.backlog.is_empty() {
+                    // Nothing is held or waits to be delivered.
+                    for done in barriers.drain(..) {
+                        let _ = done.send(());
+                    }
+                }
+                let backlog_sender = output.backlog_sender();
+                // Don't take more results from the loaders until the consumer
+                // catches up.
+                let loaded =
+                    if backlog_sender.is_some() { &never_loaded } else { &loaded_receiver };
+                let event = select! {
                     // Received request from the caller. If this channel is
                     // closed, we should shutdown everything.
                     recv(input_receiver) -> t => match t {
                         Err(RecvError) => {
                             drop(input_receiver);
+                            // Don't wait for the loads to finish.
+                            loading.values().for_each(CancelToken::cancel);
                             break
                         },
                         Ok(Task::AddRoot { root }) => {
-                            watch_root(watcher.as_mut().ok(), &mut output_sender, &*roots, root);
+                            start_load(&mut loading, &queue, &load_sender, &roots, root, None);
+                            continue;
+                        }
+                        // Changes are held until the root is validated, so
+                        // that they come after the differences.
+                        Ok(Task::ValidateRoot { root, files }) => {
+                            let cached = Some(files);
+                            start_load(&mut loading, &queue, &load_sender, &roots, root, cached);
+                            continue;
                         }
-                        Ok(Task::NotifyChanged { path }) => {
-                            handle_notify_changed(&mut output_sender, &*roots, path);
+                        Ok(Task::CancelLoad { root }) => match loading.get(&root) {
+                            Some(cancel) => {
+                                cancel.cancel();
+                                // If no loader has picked the root up, report it
+                                // right away.
+                                if !queue.remove(root) {
+                                    continue;
+                                }
+                                Event::Loaded(TaskResult::BulkLoadRoot { root, files: Vec::new() })
+                            }
+                            None => continue,
+                        },
+                        Ok(Task::Prioritize { root }) => {
+                            queue.prioritize(root);
+                            continue;
+                        }
+                        Ok(Task::UpdateRoots { roots: new_roots }) => {
+                            roots = new_roots;
+                            continue;
+                        }
+                        Ok(Task::NotifyChanged { path, origin }) => {
+                            Event::Change(HeldChange::Notify(path, origin))
+                        }
+                        Ok(Task::ValidateArchive { root, metadata }) => {
+                            let mut sender = |task| output.emit(task);
+                            validate_archive(watcher, &mut sender, &*fs, &roots, root, metadata);
+                            continue;
                         }
+                        #[cfg(feature = "test-support")]
+                        Ok(Task::Barrier { done }) => Event::Barrier(done),
+                    },
+                    // A loader has loaded a root, or a chunk of it.
+                    recv(loaded) -> res => match res.unwrap() {
+                        Loaded::Result(res) => Event::Loaded(res),
+                        Loaded::Validated(root) => Event::Validated(root),
                     },
                     // Watcher send us changes. If **this** channel is
                     // closed, the watcher has died, which indicates a bug
                     // -- escalate!
                     recv(watcher_receiver) -> event => match event {
                         Err(RecvError) => panic!("watcher is dead"),
-                        Ok((path, change)) => {
-                            handle_change(watcher.as_mut().ok(), &mut output_sender, &*roots, path, change);
+                        Ok((path, change)) => Event::Change(HeldChange::Watcher(path, change)),
+                    },
+                    // The consumer has caught up a bit. The task is only
+                    // taken out of the backlog if this is selected.
+                    send(backlog_sender.as_ref().unwrap_or(&idle_sender), output.pop_backlog()) -> res => {
+                        if res.is_err() {
+                            log::warn!("the receiver of VFS tasks is gone");
                         }
+                        continue;
                     },
+                };
+                let mut sender = |task| output.emit(task);
+                let loaded_root = match event {
+                    Event::Change(change) => {
+                        if is_loading(&loading, &roots, change.path()) {
+                            held.push(change);
+                        } else {
+                            handle_held_change(watcher, &mut sender, &*fs, &roots, change);
+                        }
+                        None
+                    }
+                    // The changes which the watcher sent before the barrier
+                    // come first.
+                    #[cfg(feature = "test-support")]
+                    Event::Barrier(done) => {
+                        for (path, kind) in watcher_receiver.try_iter() {
+                            let change = HeldChange::Watcher(path, kind);
+                            if is_loading(&loading, &roots, change.path()) {
+                                held.push(change);
+                            } else {
+                                handle_held_change(watcher, &mut sender, &*fs, &roots, change);
+                            }
+                        }
+                        barriers.push(done);
+                        None
+                    }
+                    // Emit the root, and then the changes which arrived while
+                    // it was loading. Chunks and progress of a root are
+                    // emitted right away.
+                    Event::Loaded(res) => {
+                        let root = match res {
+                            TaskResult::BulkLoadRoot { root, .. } => Some(root),
+                            _ => None,
+                        };
+                        sender(VfsTask(res));
+                        root
+                    }
+                    Event::Validated(root) => Some(root),
+                };
+                if let Some(root) = loaded_root {
+                    loading.remove(&root);
+                    for change in mem::take(&mut held) {
+                        if is_loading(&loading, &roots, change.path()) {
+                            held.push(change);
+                        } else {
+                            handle_held_change(watcher, &mut sender, &*fs, &roots, change);
+                        }
+                    }
                 }
             }
+            // Unblock the loaders, so that they can be joined.
+            drop(loaded_receiver);
         }
+        #[cfg(feature = "test-support")]
+        thread_changes.lock().take();
         // Drain pending events: we are not interested in them anyways!
         watcher_receiver.into_iter().for_each(|_| ());
     });
-    Worker { sender: input_sender, _thread }
+    Worker {
+        sender: input_sender,
+        _thread,
+        #[cfg(feature = "test-support")]
+        changes,
+    }
+}
+
+/// Where the results of the `vfs` thread go.
+pub(crate) enum Output {
+    Callback(Box<dyn FnMut(VfsTask) + Send>),
+    /// A bounded channel. When it is full, results wait in a backlog.
+    Channel(Sender<VfsTask>),
 }
 
-fn watch_root(
-    watcher: Option<&mut RecommendedWatcher>,
+struct Delivery {
+    output: Output,
+    /// Results waiting for room in the channel. Superseded `SingleFile`
+    /// results are left as `None`, and are skipped at the front, so that the
+    /// front is never `None`.
+    backlog: VecDeque<Option<TaskResult>>,
+    /// Number of results taken from the front of the backlog so far, to
+    /// turn the slots below into backlog indices.
+    n_popped: u64,
+    /// Slots of the `SingleFile` results in the backlog, by file.
+    pending_files: FxHashMap<(VfsRoot, RelativePathBuf), u64>,
+}
+
+impl Delivery {
+    fn emit(&mut self, task: VfsTask) {
+        let sender = match &mut self.output {
+            Output::Callback(callback) => return callback(task),
+            Output::Channel(sender) => sender,
+        };
+        let VfsTask(res) = if self.backlog.is_empty() {
+            match sender.try_send(task) {
+                Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(task)) => task,
+            }
+        } else {
+            task
+        };
+        if let TaskResult::SingleFile { root, path, .. } = &res {
+            // A `SingleFile` result is the current state of the file, so it
+            // supersedes the one in the backlog. The new result still goes to
+            // the back, after the results it may depend on, like a reload of
+            // the root.
+            let slot = self.n_popped + self.backlog.len() as u64;
+            if let Some(old) = self.pending_files.insert((*root, path.clone()), slot) {
+                self.backlog[(old - self.n_popped) as usize] = None;
+            }
+        }
+        self.backlog.push_back(Some(res));
+        self.skip_superseded();
+    }
+
+    /// Returns the channel to deliver the backlog to, if there is a backlog.
+    fn backlog_sender(&self) -> Option<Sender<VfsTask>> {
+        match &self.output {
+            Output::Channel(sender) if !self.backlog.is_empty() => Some(sender.clone()),
+            _ => None,
+        }
+    }
+
+    fn pop_backlog(&mut self) -> VfsTask {
+        let res = self.backlog.pop_front().unwrap().unwrap();
+        self.n_popped += 1;
+        if let TaskResult::SingleFile { root, path, .. } = &res {
+            self.pending_files.remove(&(*root, path.clone()));
+        }
+        self.skip_superseded();
+        VfsTask(res)
+    }
+
+    fn skip_superseded(&mut self) {
+        while let Some(None) = self.backlog.front() {
+            self.backlog.pop_front();
+            self.n_popped += 1;
+        }
+    }
+}
+
+/// The `vfs` thread handles either a change of a file, or a result of a
+/// loader.
+enum Event {
+    Change(HeldChange),
+    Loaded(TaskResult),
+    Validated(VfsRoot),
+    #[cfg(feature = "test-support")]
+    Barrier(Sender<()>),
+}
+
+/// Queues `root` to be loaded, or validated if it was served from the
+/// `cached` files.
+fn start_load(
+    loading: &mut FxHashMap<VfsRoot, CancelToken>,
+    queue: &LoadQueue,
+    load_sender: &Sender<()>,
+    roots: &Arc<Roots>,
+    root: VfsRoot,
+    cached: Option<Vec<(RelativePathBuf, Metadata, u64)>>,
+) {
+    let cancel = CancelToken::default();
+    loading.insert(root, cancel.clone());
+    let priority = roots.priority(root);
+    queue.push(LoadRoot { roots: Arc::clone(roots), root, priority, cancel, cached });
+    load_sender.send(()).unwrap();
+}
+
+/// Starts the threads which load roots from the `queue`, returning them
+/// together with the channel to wake them up with.
+fn start_loaders(
+    watcher: Option<SharedWatcher>,
+    fs: Arc<dyn FileSystem>,
+    queue: Arc<LoadQueue>,
+    loaded_sender: Sender<Loaded>,
+) -> (Vec<jod_thread::JoinHandle<()>>, Sender<()>) {
+    // Shared, so that identical texts of different roots are interned too.
+    let interner = Arc::new(TextInterner::default());
+    let (load_sender, load_receiver) = unbounded::<()>();
+    let loaders = (0..N_LOADERS)
+        .map(|i| {
+            let (watcher, fs, queue) = (watcher.clone(), Arc::clone(&fs), Arc::clone(&queue));
+            let interner = Arc::clone(&interner);
+            let (load_receiver, loaded_sender) = (load_receiver.clone(), loaded_sender.clone());
+            spawn(&format!("vfs-loader-{}", i), move || {
+                for () in load_receiver {
+                    // Cancelled roots are removed from the queue.
+                    let LoadRoot { roots, root, cancel, cached, .. } = match queue.pop() {
+                        Some(it) => it,
+                        None => continue,
+                    };
+                    let mut gone = false;
+                    let mut sender = |res| gone |= loaded_sender.send(Loaded::Result(res)).is_err();
+                    let watcher = watcher.as_deref();
+                    match cached {
+                        Some(files) => {
+                            validate_root(watcher, &*fs, &roots, root, files, &cancel, &mut sender);
+                            gone |= loaded_sender.send(Loaded::Validated(root)).is_err();
+                        }
+                        None => {
+                            let interner = &*interner;
+                            load_root(watcher, &*fs, &roots, root, &cancel, interner, &mut sender)
+                        }
+                    }
+                    if gone {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect();
+    (loaders, load_sender)
+}
+
+fn is_loading(loading: &FxHashMap<VfsRoot, CancelToken>, roots: &Roots, path: &Path) -> bool {
+    loading.keys().any(|&root| path.starts_with(roots.path(root)))
+}
+
+fn handle_held_change(
+    watcher: Option<&Mutex<RecommendedWatcher>>,
     sender: &mut dyn FnMut(VfsTask),
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    change: HeldChange,
+) {
+    match change {
+        HeldChange::Watcher(path, kind) => handle_change(watcher, sender, fs, roots, path, kind),
+        HeldChange::Notify(path, origin) => handle_notify_changed(sender, fs, roots, path, origin),
+    }
+}
+
+/// Loads a root, emitting its files in chunks, and its progress. The last
+/// chunk is emitted as `BulkLoadRoot`, also if the load is cancelled.
+fn load_root(
+    watcher: Option<&Mutex<RecommendedWatcher>>,
+    fs: &dyn FileSystem,
     roots: &Roots,
     root: VfsRoot,
+    cancel: &CancelToken,
+    interner: &TextInterner,
+    sender: &mut dyn FnMut(TaskResult),
 ) {
     let root_path = roots.path(root);
     log::debug!("loading {} ...", root_path.display());
-    let files = watch_recursive(watcher, root_path, roots, root)
+    let mut chunk = Chunk::new(root, interner);
+    if roots.kind(root) == RootKind::Archive {
+        // Watch the parent directory rather than the archive itself, so that
+        // we notice if the archive is replaced.
+        if let (Some(watcher), Some(dir)) = (watcher, root_path.parent()) {
+            watch_one(watcher, dir);
+        }
+        for (path, contents) in load_archive(fs, roots, root) {
+            if cancel.is_cancelled() {
+                break;
+            }
+            chunk.push(path, Some(contents), sender);
+        }
+    } else if roots.is_lazy(root) {
+        for (path, _) in watch_recursive(watcher, fs, root_path, roots, root, cancel) {
+            chunk.push(path, None, sender);
+        }
+    } else {
+        let paths = watch_recursive(watcher, fs, root_path, roots, root, cancel);
+        let n_files_discovered = paths.len();
+        sender(TaskResult::Progress(VfsProgress { root, n_files_discovered, n_files_read: 0 }));
+        for (i, (path, _)) in paths.into_iter().enumerate() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let n_files_read = i + 1;
+            if n_files_read % PROGRESS_STEP == 0 {
+                let progress = VfsProgress { root, n_files_discovered, n_files_read };
+                sender(TaskResult::Progress(progress));
+            }
+            let abs_path = path.to_path(root_path);
+            if let Some(contents) = read_to_string(fs, &abs_path) {
+                chunk.push(path, Some(contents), sender);
+            }
+        }
+    }
+    if cancel.is_cancelled() {
+        log::debug!("... cancelled loading {}", root_path.display());
+    } else {
+        log::debug!("... loaded {}", root_path.display());
+    }
+    sender(TaskResult::BulkLoadRoot { root, files: chunk.files });
+}
+
+/// Loads `root` on the calling thread, without watching it.
+pub(crate) fn load_root_sync(
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    root: VfsRoot,
+    sender: &mut dyn FnMut(TaskResult),
+) {
+    let interner = TextInterner::default();
+    load_root(None, fs, roots, root, &CancelToken::default(), &interner, sender)
+}
+
+/// Reads the file at `path` on the calling thread.
+pub(crate) fn notify_changed_sync(
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    path: PathBuf,
+    sender: &mut dyn FnMut(TaskResult),
+) {
+    let origin = ChangeOrigin::Notification;
+    handle_notify_changed(&mut |VfsTask(res)| sender(res), fs, roots, path, origin)
+}
+
+/// Files of a root being loaded, which are emitted as `LoadRootChunk` once
+/// there are `CHUNK_FILES` of them, or `CHUNK_BYTES` of text. Texts are
+/// interned as they are added.
+struct Chunk<'a> {
+    root: VfsRoot,
+    interner: &'a TextInterner,
+    files: Vec<(RelativePathBuf, Option<FileContents>)>,
+    bytes: usize,
+}
+
+impl<'a> Chunk<'a> {
+    fn new(root: VfsRoot, interner: &'a TextInterner) -> Chunk<'a> {
+        Chunk { root, interner, files: Vec::new(), bytes: 0 }
+    }
+
+    fn push(
+        &mut self,
+        path: RelativePathBuf,
+        mut contents: Option<FileContents>,
+        sender: &mut dyn FnMut(TaskResult),
+    ) {
+        if let Some(contents) = &mut contents {
+            self.interner.intern(contents);
+        }
+        self.bytes += contents.as_ref().map_or(0, |it| it.text.len());
+        self.files.push((path, contents));
+        if self.files.len() >= CHUNK_FILES || self.bytes >= CHUNK_BYTES {
+            self.bytes = 0;
+            sender(TaskResult::LoadRootChunk {
+                root: self.root,
+                files: mem::take(&mut self.files),
+            });
+        }
+    }
+}
+
+/// Watches a disk root which was served from the `cached` files, and emits
+/// the files which differ from them. A file whose metadata matches is
+/// assumed to be unchanged, without reading it.
+fn validate_root(
+    watcher: Option<&Mutex<RecommendedWatcher>>,
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    root: VfsRoot,
+    cached: Vec<(RelativePathBuf, Metadata, u64)>,
+    cancel: &CancelToken,
+    sender: &mut dyn FnMut(TaskResult),
+) {
+    let root_path = roots.path(root);
+    log::debug!("validating the cache of {} ...", root_path.display());
+    let mut cached = cached
         .into_iter()
-        .filter_map(|path| {
-            let abs_path = path.to_path(&root_path);
-            let (text, line_endings) = read_to_string(&abs_path)?;
-            Some((path, text, line_endings))
-        })
-        .collect();
-    let res = TaskResult::BulkLoadRoot { root, files };
-    sender(VfsTask(res));
-    log::debug!("... loaded {}", root_path.display());
+        .map(|(path, metadata, hash)| (path, (metadata, hash)))
+        .collect::<FxHashMap<_, _>>();
+    let origin = ChangeOrigin::Reconcile;
+    for (path, metadata) in watch_recursive(watcher, fs, root_path, roots, root, cancel) {
+        if cancel.is_cancelled() {
+            return;
+        }
+        let hash = match cached.remove(&path) {
+            Some((it, _)) if it.modified.is_some() && it == metadata => continue,
+            Some((_, hash)) => Some(hash),
+            None => None,
+        };
+        let contents = read_to_string(fs, &path.to_path(root_path));
+        match (&contents, hash) {
+            (None, None) => continue,
+            (Some(contents), Some(hash)) if contents.hash == hash => continue,
+            _ => {
+                let time = SystemTime::now();
+                sender(TaskResult::SingleFile { root, path, contents, origin, time })
+            }
+        }
+    }
+    if cancel.is_cancelled() {
+        return;
+    }
+    for (path, _) in cached {
+        let time = SystemTime::now();
+        sender(TaskResult::SingleFile { root, path, contents: None, origin, time });
+    }
+    log::debug!("... validated the cache of {}", root_path.display());
+}
+
+fn validate_archive(
+    watcher: Option<&Mutex<RecommendedWatcher>>,
+    sender: &mut dyn FnMut(VfsTask),
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    root: VfsRoot,
+    cached: Metadata,
+) {
+    let root_path = roots.path(root);
+    if let (Some(watcher), Some(dir)) = (watcher, root_path.parent()) {
+        watch_one(watcher, dir);
+    }
+    match fs.metadata(root_path) {
+        Ok(metadata) if metadata == cached => {
+            log::debug!("cache of {} is up to date", root_path.display())
+        }
+        _ => reload_archive(sender, fs, roots, root, ChangeOrigin::Reconcile),
+    }
 }
 
 fn convert_notify_event(event: DebouncedEvent, sender: &Sender<(PathBuf, ChangeKind)>) {
@@ -216,91 +875,195 @@ fn convert_notify_event(event: DebouncedEvent, sender: &Sender<(PathBuf, ChangeK
 }
 
 fn handle_change(
-    watcher: Option<&mut RecommendedWatcher>,
+    watcher: Option<&Mutex<RecommendedWatcher>>,
     sender: &mut dyn FnMut(VfsTask),
+    fs: &dyn FileSystem,
     roots: &Roots,
     path: PathBuf,
     kind: ChangeKind,
 ) {
-    let ft = if path.is_file() { FileType::File } else { FileType::Dir };
+    let ft = match fs.metadata(&path) {
+        Ok(metadata) if !metadata.is_dir => FileType::File,
+        _ => FileType::Dir,
+    };
     let (root, rel_path) = match roots.find(&path, ft) {
-        None => return,
-        Some(it) => it,
+        Some(it) if roots.kind(it.0) == RootKind::Disk => it,
+        Some((root, rel_path))
+            if roots.kind(root) == RootKind::Archive && rel_path.as_str().is_empty() =>
+        {
+            return reload_archive(sender, fs, roots, root, ChangeOrigin::Watcher);
+        }
+        _ => return,
     };
+    let origin = ChangeOrigin::Watcher;
     match kind {
         ChangeKind::Create => {
             let mut paths = Vec::new();
             if ft.is_dir() {
-                paths.extend(watch_recursive(watcher, &path, roots, root));
+                let cancel = CancelToken::default();
+                let files = watch_recursive(watcher, fs, &path, roots, root, &cancel);
+                paths.extend(files.into_iter().map(|(path, _)| path));
             } else {
                 paths.push(rel_path);
             }
             paths.into_iter().for_each(|rel_path| {
-                let abs_path = rel_path.to_path(&roots.path(root));
-                let (text, line_endings) = match read_to_string(&abs_path) {
-                    Some((text, line_endings)) => (Some(text), line_endings),
-                    None => (None, LineEndings::default()),
-                };
-
-                let res = TaskResult::SingleFile { root, path: rel_path, text, line_endings };
+                let abs_path = rel_path.to_path(roots.path(root));
+                let contents = read_to_string(fs, &abs_path);
+                let time = SystemTime::now();
+                let res = TaskResult::SingleFile { root, path: rel_path, contents, origin, time };
                 sender(VfsTask(res))
             })
         }
         ChangeKind::Write | ChangeKind::Remove => {
-            let (text, line_endings) = match read_to_string(&path) {
-                Some((text, line_endings)) => (Some(text), line_endings),
-                None => (None, LineEndings::default()),
-            };
-            let res = TaskResult::SingleFile { root, path: rel_path, text, line_endings };
+            let contents = read_to_string(fs, &path);
+            let time = SystemTime::now();
+            let res = TaskResult::SingleFile { root, path: rel_path, contents, origin, time };
             sender(VfsTask(res));
         }
     }
 }
 
 fn watch_recursive(
-    mut watcher: Option<&mut RecommendedWatcher>,
+    watcher: Option<&Mutex<RecommendedWatcher>>,
+    fs: &dyn FileSystem,
     dir: &Path,
     roots: &Roots,
     root: VfsRoot,
-) -> Vec<RelativePathBuf> {
+    cancel: &CancelToken,
+) -> Vec<(RelativePathBuf, Metadata)> {
     let mut files = Vec::new();
-    // FIXME: this is broken for symlinks at the moment
-    for entry in WalkDir::new(dir)
-        .into_iter()
-        .filter_entry(|it| roots.contains(root, it.path(), it.file_type().into()).is_some())
-        .filter_map(|it| it.map_err(|e| log::warn!("watcher error: {}", e)).ok())
-    {
-        if entry.file_type().is_dir() {
-            if let Some(watcher) = &mut watcher {
-                watch_one(watcher, entry.path());
+    let entries = fs.walk(dir, &mut |path, metadata| {
+        // Skipping everything stops the walk as soon as possible.
+        !cancel.is_cancelled() && roots.contains(root, path, metadata.into()).is_some()
+    });
+    for (path, metadata) in entries {
+        if metadata.is_dir {
+            if let Some(watcher) = watcher {
+                watch_one(watcher, &path);
             }
-        } else if let Some(path) = roots.contains(root, entry.path(), FileType::File) {
-            files.push(path.to_owned());
+        } else if let Some(path) = roots.contains(root, &path, FileType::File) {
+            files.push((path, metadata));
         }
     }
     files
 }
 
-fn watch_one(watcher: &mut RecommendedWatcher, dir: &Path) {
-    match watcher.watch(dir, RecursiveMode::NonRecursive) {
+fn watch_one(watcher: &Mutex<RecommendedWatcher>, dir: &Path) {
+    match watcher.lock().watch(dir, RecursiveMode::NonRecursive) {
         Ok(()) => log::debug!("watching \"{}\"", dir.display()),
         Err(e) => log::warn!("could not watch \"{}\": {}", dir.display(), e),
     }
 }
 
-fn handle_notify_changed(sender: &mut dyn FnMut(VfsTask), roots: &Roots, path: PathBuf) {
-    if !path.is_file() {
-        return;
-    };
+fn handle_notify_changed(
+    sender: &mut dyn FnMut(VfsTask),
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    path: PathBuf,
+    origin: ChangeOrigin,
+) {
+    match fs.metadata(&path) {
+        Ok(metadata) if !metadata.is_dir => (),
+        _ => return,
+    }
     let (root, rel_path) = match roots.find(&path, FileType::File) {
-        None => return,
-        Some(it) => it,
+        Some(it) if roots.kind(it.0) == RootKind::Disk => it,
+        Some((root, rel_path))
+            if roots.kind(root) == RootKind::Archive && rel_path.as_str().is_empty() =>
+        {
+            return reload_archive(sender, fs, roots, root, origin);
+        }
+        _ => return,
     };
-    let (text, line_endings) = match read_to_string(&path) {
-        Some((text, line_endings)) => (Some(text), line_endings),
-        None => (None, LineEndings::default()),
+    let contents = read_to_string(fs, &path);
+    let time = SystemTime::now();
+    let res = TaskResult::SingleFile { root, path: rel_path, contents, origin, time };
+    sender(VfsTask(res))
+}
+
+fn reload_archive(
+    sender: &mut dyn FnMut(VfsTask),
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    root: VfsRoot,
+    origin: ChangeOrigin,
+) {
+    log::debug!("reloading {} ...", roots.path(root).display());
+    let files = load_archive(fs, roots, root);
+    let res = TaskResult::ReloadRoot { root, files, origin, time: SystemTime::now() };
+    sender(VfsTask(res));
+}
+
+pub(crate) fn load_archive(
+    fs: &dyn FileSystem,
+    roots: &Roots,
+    root: VfsRoot,
+) -> Vec<(RelativePathBuf, FileContents)> {
+    let root_path = roots.path(root);
+    let entries = match fs.read(root_path).and_then(|bytes| archive::read_archive(&bytes)) {
+        Ok(it) => it,
+        Err(e) => {
+            log::warn!("failed to read archive {}: {}", root_path.display(), e);
+            return Vec::new();
+        }
     };
+    entries
+        .into_iter()
+        .filter(|(path, _)| {
+            // Archives are not walked directory by directory, so check that
+            // every parent directory is included.
+            let mut dir = path.parent();
+            while let Some(it) = dir {
+                if !it.as_str().is_empty()
+                    && roots.contains(root, &it.to_path(root_path), FileType::Dir).is_none()
+                {
+                    return false;
+                }
+                dir = it.parent();
+            }
+            roots.contains(root, &path.to_path(root_path), FileType::File).is_some()
+        })
+        .filter_map(|(path, contents)| {
+            let text = String::from_utf8(contents)
+                .map_err(|e| log::warn!("failed to read {} from archive: {}", path, e))
+                .ok()?;
+            Some((path, FileContents::new(text)))
+        })
+        .collect()
+}
 
-    let res = TaskResult::SingleFile { root, path: rel_path, text, line_endings };
-    sender(VfsTask(res))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::NoopFilter, MemoryFileSystem, RootEntry};
+
+    #[test]
+    fn load_queue_orders_roots_by_priority() {
+        let mut entries = (0..4)
+            .map(|i| RootEntry::new(format!("/lib{}", i).into(), Box::new(NoopFilter)))
+            .collect::<Vec<_>>();
+        entries.push(RootEntry::new("/ws".into(), Box::new(NoopFilter)).priority(1));
+        let roots = Arc::new(Roots::new(entries, &MemoryFileSystem::new()));
+        let root = |path: &str| roots.find(Path::new(path), FileType::Dir).unwrap().0;
+
+        let queue = LoadQueue::default();
+        for &path in ["/lib0", "/lib1", "/ws", "/lib2", "/lib3"].iter() {
+            let (root, priority) = (root(path), roots.priority(root(path)));
+            let cancel = CancelToken::default();
+            queue.push(LoadRoot {
+                roots: Arc::clone(&roots),
+                root,
+                priority,
+                cancel,
+                cached: None,
+            });
+        }
+        queue.prioritize(root("/lib3"));
+        assert!(queue.remove(root("/lib1")));
+        assert!(!queue.remove(root("/lib1")));
+
+        let order = std::iter::from_fn(|| queue.pop()).map(|it| it.root).collect::<Vec<_>>();
+        let expected = ["/lib3", "/ws", "/lib0", "/lib2"].iter().map(|&it| root(it));
+        assert_eq!(order, expected.collect::<Vec<_>>());
+    }
 }
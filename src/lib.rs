@@ -15,37 +15,96 @@
 //! VFS is based on a concept of roots: a set of directories on the file system
 //! which are watched for changes. Typically, there will be a root for each
 //! Cargo package.
+//!
+//! Overlays for files which do not belong to any root (scratch files, unsaved
+//! editor buffers) are put into *detached* roots. A detached root contains a
+//! single file, is never watched or read from disk, and is removed with the
+//! overlay.
+//!
+//! Files which do not exist on disk at all, like generated sources, can be
+//! added as *virtual* files, either to an existing root or to a virtual root.
+//!
+//! All disk access goes through the `FileSystem` trait, so the VFS can be
+//! backed by an in-memory file system in tests.
+//!
+//! `Vfs::snapshot` returns an immutable `VfsSnapshot` of the current state,
+//! which can be handed to other threads while the `Vfs` keeps changing.
+//!
+//! To speed up startup, the contents of the roots can be saved as a
+//! `VfsCache` and passed to `Vfs::with_cache` on the next run.
+//!
+//! Results of the IO thread are delivered as `VfsTask`s to a callback, or
+//! through a channel with `Vfs::with_channel`. With the `futures` feature,
+//! `Vfs::with_stream` delivers them as a `Stream` for async runtimes.
+//!
+//! To reproduce bugs, the inputs of a VFS can be recorded to a file with
+//! `Vfs::start_recording`, and replayed on an in-memory file system with
+//! `Replay`.
+//!
+//! The `test_support` module, enabled by the `test-support` feature, has a
+//! fake watcher and a manual clock, to test code which uses the VFS without
+//! sleeps or timeouts.
 mod roots;
 mod io;
+mod file_system;
+mod archive;
+mod snapshot;
+mod cache;
+mod hash;
+mod memory;
+mod change_set;
+mod recording;
+mod subscription;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+#[cfg(feature = "futures")]
+mod stream;
 
 use std::{
-    fmt, fs, mem,
+    collections::BTreeSet,
+    fmt, mem,
+    ops::Bound,
     path::{Path, PathBuf},
     sync::Arc,
+    time::SystemTime,
 };
 
+use crossbeam_channel::Receiver;
+use parking_lot::Mutex;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
     io::{TaskResult, Worker},
-    roots::{Roots, FileType},
+    roots::{Roots, RootKind, FileType},
+    snapshot::SharedVec,
+    cache::{CachedFile, CachedRoot},
+    memory::MemoryState,
+    subscription::ChangeLog,
+    recording::{Event, Recorder},
+    hash::RootHash,
 };
 
 pub use relative_path::{RelativePath, RelativePathBuf};
-pub use crate::roots::VfsRoot;
+pub use crate::{
+    roots::VfsRoot,
+    file_system::{FileSystem, Metadata, MemoryFileSystem, OsFileSystem},
+    snapshot::VfsSnapshot,
+    cache::VfsCache,
+    memory::MemoryStats,
+    change_set::{ChangeSet, RootChanges},
+    recording::{Recording, Replay},
+    subscription::{ChangeFilter, Subscription},
+};
+#[cfg(feature = "futures")]
+pub use crate::stream::VfsTaskStream;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub enum LineEndings {
+    #[default]
     Unix,
     Dos,
 }
 
-impl Default for LineEndings {
-    fn default() -> Self {
-        LineEndings::Unix
-    }
-}
-
 /// a `Filter` is used to determine whether a file or a folder
 /// under the specific root is included.
 ///
@@ -87,6 +146,9 @@ pub trait Filter: Send + Sync {
 pub struct RootEntry {
     path: PathBuf,
     filter: Box<dyn Filter>,
+    kind: RootKind,
+    lazy: bool,
+    priority: i32,
 }
 
 impl std::fmt::Debug for RootEntry {
@@ -107,7 +169,40 @@ impl RootEntry {
     /// Create a new `RootEntry` with the given `filter` applied to
     /// files and folder under it.
     pub fn new(path: PathBuf, filter: Box<dyn Filter>) -> Self {
-        RootEntry { path, filter }
+        RootEntry { path, filter, kind: RootKind::Disk, lazy: false, priority: 0 }
+    }
+
+    /// Create a new `RootEntry` for a `.crate` or `.tar.gz` archive at `path`.
+    ///
+    /// If all entries of the archive are inside a single top-level directory
+    /// (which is the case for `.crate` files), paths are relative to that
+    /// directory. The `filter` is applied to the archive entries.
+    ///
+    /// Files of an archive root are immutable: overlays for them are ignored,
+    /// and the whole root is reloaded if the archive changes on disk.
+    pub fn archive(path: PathBuf, filter: Box<dyn Filter>) -> Self {
+        RootEntry { path, filter, kind: RootKind::Archive, lazy: false, priority: 0 }
+    }
+
+    /// Makes the root lazy: the initial scan only lists the files, and their
+    /// texts are read on first access, with `Vfs::load_text` or
+    /// `Vfs::request_text`.
+    ///
+    /// This is useful for large library roots, most files of which are never
+    /// looked at. Only disk roots can be lazy: the files of an archive are
+    /// decompressed together, so archive roots are always loaded whole, and
+    /// `lazy` has no effect on them.
+    pub fn lazy(mut self) -> Self {
+        self.lazy = self.kind == RootKind::Disk;
+        self
+    }
+
+    /// Sets the priority of loading the root, `0` by default. Roots with
+    /// higher priorities are loaded first, so that, for example, the
+    /// workspace does not wait for the libraries it depends on.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
     }
 }
 /// Opaque wrapper around file-system event.
@@ -117,30 +212,96 @@ impl RootEntry {
 /// main event loop and be notified when changes happen.
 pub struct VfsTask(TaskResult);
 
+impl VfsTask {
+    /// Returns the progress of loading a root, if this task reports it.
+    ///
+    /// Progress tasks still have to be passed to `handle_task`, but they do not
+    /// change the state of the VFS.
+    pub fn progress(&self) -> Option<VfsProgress> {
+        match self.0 {
+            TaskResult::Progress(it) => Some(it),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Debug for VfsTask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("VfsTask { ... }")
+        match self.0 {
+            TaskResult::Progress(it) => write!(f, "VfsTask({:?})", it),
+            _ => f.write_str("VfsTask { ... }"),
+        }
     }
 }
 
+/// Progress of the initial load of a root.
+///
+/// Reported for disk roots which are not lazy: once after the root has been
+/// scanned, and then periodically while the files are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsProgress {
+    pub root: VfsRoot,
+    pub n_files_discovered: usize,
+    pub n_files_read: usize,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VfsFile(pub u32);
 
+#[derive(Clone)]
 struct VfsFileData {
     root: VfsRoot,
     path: RelativePathBuf,
     is_overlayed: bool,
-    text: Arc<String>,
-    line_endings: LineEndings,
+    /// Document version reported by the client for the overlay, if any.
+    overlay_version: Option<i64>,
+    /// Text of a virtual file, which is used instead of the disk contents.
+    virtual_text: Option<Arc<String>>,
+    /// `None` if the file belongs to a lazy root and was not loaded yet, or
+    /// if the contents were evicted.
+    contents: Option<FileContents>,
+    /// Hash and line endings of the evicted contents.
+    evicted: Option<(u64, LineEndings)>,
+    /// Time of the last access to the file, by the clock of `MemoryState`.
+    last_accessed: u64,
+    /// Revision of the VFS at which this file was last added, changed or
+    /// removed.
+    last_changed: u64,
 }
 
+/// The `files` and `unloaded` files of an `AddRoot` or `AddRootChunk`.
+type SplitFiles = (Vec<(VfsFile, RelativePathBuf, Arc<String>)>, Vec<(VfsFile, RelativePathBuf)>);
+
 pub struct Vfs {
     roots: Arc<Roots>,
-    files: Vec<VfsFileData>,
-    root2files: FxHashMap<VfsRoot, FxHashSet<VfsFile>>,
+    fs: Arc<dyn FileSystem>,
+    files: SharedVec<VfsFileData>,
+    root2files: FxHashMap<VfsRoot, Arc<FxHashSet<VfsFile>>>,
+    /// Hashes of the roots, updated as their files change.
+    root_hashes: FxHashMap<VfsRoot, RootHash>,
     pending_changes: Vec<VfsChange>,
-    #[allow(unused)]
-    worker: Worker,
+    /// Changes for the subscribers, see `Vfs::subscribe`.
+    change_log: ChangeLog,
+    /// Incremented on every change of the VFS state.
+    revision: u64,
+    /// The files with the revisions they last changed at, ordered by
+    /// revision, for `changed_since`.
+    files_by_revision: BTreeSet<(u64, VfsFile)>,
+    /// Revision at the last `commit_changes`.
+    committed_revision: u64,
+    memory: MemoryState,
+    /// Roots whose initial load has not finished yet, with the files which
+    /// were reported in `AddRootChunk`s.
+    loading_roots: FxHashMap<VfsRoot, FxHashSet<VfsFile>>,
+    /// `None` if the VFS was loaded with `load_sync`: then everything is done
+    /// on the calling thread.
+    worker: Option<Worker>,
+    /// Origin of the changes which are being applied, see `with_origin`.
+    origin: ChangeOrigin,
+    /// Time of the changes which are being applied, if they didn't happen
+    /// just now, see `at_time`.
+    time: Option<SystemTime>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
 }
 
 impl fmt::Debug for Vfs {
@@ -154,11 +315,96 @@ impl fmt::Debug for Vfs {
 }
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum VfsChange {
-    AddRoot { root: VfsRoot, files: Vec<(VfsFile, RelativePathBuf, Arc<String>)> },
-    AddFile { root: VfsRoot, file: VfsFile, path: RelativePathBuf, text: Arc<String> },
-    RemoveFile { root: VfsRoot, file: VfsFile, path: RelativePathBuf },
-    ChangeFile { file: VfsFile, text: Arc<String> },
+    /// `unloaded` are the files of a lazy root whose texts were not loaded
+    /// yet. Their texts are reported with `ChangeFile` once loaded.
+    ///
+    /// Large roots are loaded in chunks: the files of a root which were
+    /// already reported with `AddRootChunk` are not repeated here.
+    AddRoot {
+        root: VfsRoot,
+        files: Vec<(VfsFile, RelativePathBuf, Arc<String>)>,
+        unloaded: Vec<(VfsFile, RelativePathBuf)>,
+        origin: ChangeOrigin,
+        time: SystemTime,
+    },
+    /// A chunk of files of a root which is still loading. The root is complete
+    /// once `AddRoot` is reported for it.
+    AddRootChunk {
+        root: VfsRoot,
+        files: Vec<(VfsFile, RelativePathBuf, Arc<String>)>,
+        unloaded: Vec<(VfsFile, RelativePathBuf)>,
+        origin: ChangeOrigin,
+        time: SystemTime,
+    },
+    AddFile {
+        root: VfsRoot,
+        file: VfsFile,
+        path: RelativePathBuf,
+        text: Arc<String>,
+        origin: ChangeOrigin,
+        time: SystemTime,
+    },
+    RemoveFile {
+        root: VfsRoot,
+        file: VfsFile,
+        path: RelativePathBuf,
+        origin: ChangeOrigin,
+        time: SystemTime,
+    },
+    /// `version` is the client's document version if the change comes from a
+    /// versioned overlay edit, and `None` otherwise.
+    ChangeFile {
+        file: VfsFile,
+        text: Arc<String>,
+        version: Option<i64>,
+        origin: ChangeOrigin,
+        time: SystemTime,
+    },
+}
+
+impl VfsChange {
+    /// Returns what caused the change.
+    pub fn origin(&self) -> ChangeOrigin {
+        match self {
+            VfsChange::AddRoot { origin, .. }
+            | VfsChange::AddRootChunk { origin, .. }
+            | VfsChange::AddFile { origin, .. }
+            | VfsChange::RemoveFile { origin, .. }
+            | VfsChange::ChangeFile { origin, .. } => *origin,
+        }
+    }
+
+    /// Returns the wall-clock time at which the change was noticed: for changes
+    /// found by the IO thread, this is when it found them rather than when they
+    /// were applied to the VFS.
+    pub fn time(&self) -> SystemTime {
+        match self {
+            VfsChange::AddRoot { time, .. }
+            | VfsChange::AddRootChunk { time, .. }
+            | VfsChange::AddFile { time, .. }
+            | VfsChange::RemoveFile { time, .. }
+            | VfsChange::ChangeFile { time, .. } => *time,
+        }
+    }
+}
+
+/// What caused a `VfsChange`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeOrigin {
+    /// An overlay was added or edited by the client.
+    Overlay,
+    /// The file changed on disk, as reported by the watcher.
+    Watcher,
+    /// The client reported a change with `Vfs::notify_changed`.
+    Notification,
+    /// The VFS brought a file back in line with its source of truth, for
+    /// example restoring the disk text when an overlay is removed, or
+    /// reloading an archive which changed.
+    Reconcile,
+    /// Any other call of the VFS API, such as loading roots and virtual files.
+    Api,
 }
 
 #[derive(Clone, Copy)]
@@ -170,19 +416,285 @@ impl Vfs {
         on_task: Box<dyn FnMut(VfsTask) + Send>,
         watch: Watch,
     ) -> (Vfs, Vec<VfsRoot>) {
-        let roots = Arc::new(Roots::new(roots));
-        let worker = io::start(Arc::clone(&roots), on_task, watch);
-        let mut root2files = FxHashMap::default();
+        Vfs::with_file_system(roots, Arc::new(OsFileSystem), on_task, watch)
+    }
 
-        for root in roots.iter() {
-            root2files.insert(root, Default::default());
-            worker.send(io::Task::AddRoot { root });
+    /// Like `new`, but reads files from `fs` instead of the OS file system.
+    ///
+    /// Note that the watcher always watches the OS file system, so it is
+    /// usually disabled for other file systems: use `notify_changed` instead.
+    pub fn with_file_system(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        on_task: Box<dyn FnMut(VfsTask) + Send>,
+        watch: Watch,
+    ) -> (Vfs, Vec<VfsRoot>) {
+        Vfs::with_cache(roots, fs, VfsCache::default(), on_task, watch)
+    }
+
+    /// Like `with_file_system`, but serves roots from the `cache` if possible.
+    ///
+    /// Cached roots are reported as `AddRoot` changes right away, and are
+    /// validated against the file system in the background: only the real
+    /// differences are reported later. Files of cached disk roots are
+    /// reported as `unloaded`, like the files of lazy roots, and their texts
+    /// are read on first access.
+    pub fn with_cache(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        cache: VfsCache,
+        on_task: Box<dyn FnMut(VfsTask) + Send>,
+        watch: Watch,
+    ) -> (Vfs, Vec<VfsRoot>) {
+        Vfs::with_output(roots, fs, cache, io::Output::Callback(on_task), watch)
+    }
+
+    fn with_output(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        mut cache: VfsCache,
+        output: io::Output,
+        watch: Watch,
+    ) -> (Vfs, Vec<VfsRoot>) {
+        let roots = Arc::new(Roots::new(roots, &*fs));
+        let worker = io::start(Arc::clone(&roots), Arc::clone(&fs), output, watch);
+        let mut res = Vfs::with_worker(roots, fs, Some(worker));
+        let vfs_roots = res.roots.iter().collect::<Vec<_>>();
+        for root in res.roots_by_priority() {
+            let cached = cache.take(res.roots.path(root));
+            match (res.roots.kind(root), cached) {
+                (RootKind::Archive, Some(CachedRoot { archive: Some(metadata), files }))
+                    if files.iter().all(|it| it.text.is_some()) =>
+                {
+                    let files = files
+                        .into_iter()
+                        .filter_map(|CachedFile { path, text, hash, line_endings, .. }| {
+                            let text = Arc::new(text?);
+                            Some((path, Some(FileContents { text, line_endings, hash })))
+                        })
+                        .collect();
+                    res.handle_task(VfsTask(TaskResult::BulkLoadRoot { root, files }));
+                    res.send_task(io::Task::ValidateArchive { root, metadata });
+                }
+                (RootKind::Disk, Some(CachedRoot { archive: None, files })) => {
+                    res.serve_cached_root(root, files)
+                }
+                _ => res.start_loading(root),
+            }
         }
-        let res = Vfs { roots, files: Vec::new(), root2files, worker, pending_changes: Vec::new() };
-        let vfs_roots = res.roots.iter().collect();
         (res, vfs_roots)
     }
 
+    /// Adds the cached `files` of a disk root without their texts, and asks
+    /// the IO thread to report the files which differ on disk.
+    fn serve_cached_root(&mut self, root: VfsRoot, files: Vec<CachedFile>) {
+        let paths = files.iter().map(|it| (it.path.clone(), None)).collect();
+        self.handle_task(VfsTask(TaskResult::BulkLoadRoot { root, files: paths }));
+        // The cached hashes stand for the texts, as if they were evicted, so
+        // that loading an unchanged text is not reported as a change.
+        let by_path = self.root2files[&root]
+            .iter()
+            .map(|&file| (self.file(file).path.clone(), file))
+            .collect::<FxHashMap<_, _>>();
+        for cached in files.iter() {
+            if let Some(&file) = by_path.get(&cached.path) {
+                self.unindex_file(file);
+                self.file_mut(file).evicted = Some((cached.hash, cached.line_endings));
+                self.index_file(file);
+            }
+        }
+        let files = files.into_iter().map(|it| (it.path, it.metadata, it.hash)).collect();
+        self.send_task(io::Task::ValidateRoot { root, files });
+    }
+
+    /// Like `with_file_system`, but returns the tasks through a channel
+    /// instead of passing them to a callback.
+    pub fn with_channel(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        watch: Watch,
+    ) -> (Vfs, Vec<VfsRoot>, Receiver<VfsTask>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let on_task = Box::new(move |task| {
+            // The receiver is only dropped if the caller is no longer
+            // interested in the tasks.
+            let _ = sender.send(task);
+        });
+        let (vfs, roots) = Vfs::with_file_system(roots, fs, on_task, watch);
+        (vfs, roots, receiver)
+    }
+
+    /// Like `with_channel`, but the channel holds at most `capacity` tasks.
+    ///
+    /// When the channel is full, the IO thread stops taking results from the
+    /// threads which load roots, so that they wait too, and coalesces the
+    /// changes of the same file: only the latest state of the file is
+    /// delivered once there is room. This bounds the memory used if the caller
+    /// falls behind, for example during a branch switch.
+    pub fn with_bounded_channel(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        watch: Watch,
+        capacity: usize,
+    ) -> (Vfs, Vec<VfsRoot>, Receiver<VfsTask>) {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let output = io::Output::Channel(sender);
+        let (vfs, roots) = Vfs::with_output(roots, fs, VfsCache::default(), output, watch);
+        (vfs, roots, receiver)
+    }
+
+    /// Loads `roots` on the calling thread, and returns the VFS with their
+    /// `AddRoot` changes pending.
+    ///
+    /// No threads are spawned and nothing is watched, which suits batch tools
+    /// that load the roots once. Changes are still picked up by
+    /// `notify_changed`, and roots added later are loaded right away, but
+    /// `request_text` does nothing: use `load_text` instead.
+    pub fn load_sync(roots: Vec<RootEntry>) -> Vfs {
+        Vfs::load_sync_with_file_system(roots, Arc::new(OsFileSystem))
+    }
+
+    /// Like `load_sync`, but reads files from `fs`.
+    pub fn load_sync_with_file_system(roots: Vec<RootEntry>, fs: Arc<dyn FileSystem>) -> Vfs {
+        let roots = Arc::new(Roots::new(roots, &*fs));
+        let mut res = Vfs::with_worker(roots, fs, None);
+        for root in res.roots_by_priority() {
+            res.start_loading(root);
+        }
+        res
+    }
+
+    fn with_worker(roots: Arc<Roots>, fs: Arc<dyn FileSystem>, worker: Option<Worker>) -> Vfs {
+        let root2files = roots.iter().map(|root| (root, Default::default())).collect();
+        Vfs {
+            roots,
+            fs,
+            files: SharedVec::new(),
+            root2files,
+            root_hashes: FxHashMap::default(),
+            worker,
+            pending_changes: Vec::new(),
+            change_log: ChangeLog::default(),
+            revision: 0,
+            committed_revision: 0,
+            files_by_revision: BTreeSet::new(),
+            memory: MemoryState::default(),
+            loading_roots: FxHashMap::default(),
+            origin: ChangeOrigin::Api,
+            time: None,
+            recorder: None,
+        }
+    }
+
+    fn roots_by_priority(&self) -> Vec<VfsRoot> {
+        let mut roots = self.roots.iter().collect::<Vec<_>>();
+        roots.sort_by_key(|&root| std::cmp::Reverse(self.roots.priority(root)));
+        roots
+    }
+
+    /// Adds a new root and starts loading it in the background.
+    ///
+    /// Files which now belong to the new root, such as overlays in detached
+    /// roots, are moved to it, keeping their `VfsFile`s: a `RemoveFile` change
+    /// is emitted for the old root, and the files are reported as a part of
+    /// `AddRoot` for the new one.
+    pub fn add_root(&mut self, entry: RootEntry) -> VfsRoot {
+        self.record(|| Event::add_root(&entry));
+        let (root, is_new) = self.add_root_without_loading(entry);
+        if is_new {
+            self.start_loading(root);
+        }
+        root
+    }
+
+    /// Adds a root which is marked as loading, but is not loaded yet. Returns
+    /// the root and whether it was not added before.
+    pub(crate) fn add_root_without_loading(&mut self, entry: RootEntry) -> (VfsRoot, bool) {
+        let mut roots = Roots::clone(&self.roots);
+        let root = roots.add(entry, &*self.fs);
+        let is_new = !self.root2files.contains_key(&root);
+        if is_new {
+            self.insert_root(roots, root);
+            self.loading_roots.insert(root, FxHashSet::default());
+        }
+        (root, is_new)
+    }
+
+    /// Adds a root which is never read from disk. Its files are added with
+    /// `add_virtual_file` or as overlays.
+    ///
+    /// Unlike `add_root`, `AddRoot` change for a virtual root is emitted
+    /// immediately.
+    pub fn add_virtual_root(&mut self, path: PathBuf) -> VfsRoot {
+        self.record(|| Event::AddVirtualRoot(path.clone()));
+        let mut roots = Roots::clone(&self.roots);
+        let root = roots.add_virtual(path, &*self.fs);
+        if !self.root2files.contains_key(&root) {
+            self.insert_root(roots, root);
+            let files = self.root2files[&root].iter().copied().collect();
+            self.push_add_root(root, files);
+        }
+        root
+    }
+
+    /// Returns `true` if the initial load of every root has finished, that is
+    /// if an `AddRoot` change has been emitted for each of them.
+    pub fn all_roots_loaded(&self) -> bool {
+        self.loading_roots.is_empty()
+    }
+
+    pub fn is_root_loaded(&self, root: VfsRoot) -> bool {
+        !self.loading_roots.contains_key(&root)
+    }
+
+    /// Loads `root` before the other roots which are waiting to be loaded,
+    /// regardless of their priorities. Does nothing if the root is already
+    /// being loaded, or is loaded.
+    ///
+    /// This is useful when the user opens a file in a root which is not
+    /// loaded yet.
+    pub fn prioritize(&self, root: VfsRoot) {
+        if !self.is_root_loaded(root) {
+            self.send_task(io::Task::Prioritize { root });
+        }
+    }
+
+    /// Like `prioritize`, but for the root which contains `path`.
+    pub fn prioritize_path(&self, path: &Path) {
+        if let Some((root, _path)) = self.roots.find(path, FileType::File) {
+            self.prioritize(root);
+        }
+    }
+
+    /// Stops loading `root`. Does nothing if the root is loaded.
+    ///
+    /// The root is reported with `AddRoot` as usual, but only with the files
+    /// which were read before the load was cancelled. This is useful to abort
+    /// loading a root which turned out to be huge, like a home directory.
+    pub fn cancel_load(&self, root: VfsRoot) {
+        if !self.is_root_loaded(root) {
+            self.send_task(io::Task::CancelLoad { root });
+        }
+    }
+
+    fn start_loading(&mut self, root: VfsRoot) {
+        self.loading_roots.insert(root, FxHashSet::default());
+        if self.worker.is_some() {
+            self.send_task(io::Task::AddRoot { root });
+            return;
+        }
+        let (fs, roots) = (Arc::clone(&self.fs), Arc::clone(&self.roots));
+        io::load_root_sync(&*fs, &roots, root, &mut |res| self.handle_task(VfsTask(res)));
+    }
+
+    /// Sends `task` to the IO thread, if there is one.
+    fn send_task(&self, task: io::Task) {
+        self.record(|| Event::task(&task));
+        if let Some(worker) = &self.worker {
+            worker.send(task);
+        }
+    }
+
     pub fn root2path(&self, root: VfsRoot) -> PathBuf {
         self.roots.path(root).to_path_buf()
     }
@@ -205,133 +717,588 @@ impl Vfs {
         rel_path.to_path(root_path)
     }
 
+    pub fn file2root(&self, file: VfsFile) -> VfsRoot {
+        self.file(file).root
+    }
+
+    /// Returns the text of `file`, or `None` if the file belongs to a lazy
+    /// root and was not loaded yet, or if its text was evicted.
+    pub fn file_text(&self, file: VfsFile) -> Option<Arc<String>> {
+        self.memory.access.read(file);
+        self.file(file).text()
+    }
+
     pub fn file_line_endings(&self, file: VfsFile) -> LineEndings {
-        self.file(file).line_endings
+        self.file(file).line_endings()
+    }
+
+    /// Returns a hash of the text of `file`, or `None` if the file was not
+    /// loaded yet.
+    ///
+    /// The hash is stable across runs and platforms, so it can be used as a
+    /// key for persistent caches.
+    pub fn file_hash(&self, file: VfsFile) -> Option<u64> {
+        self.file(file).hash()
+    }
+
+    /// Returns an aggregate hash of the paths and texts of all files in
+    /// `root`, or `None` if some of the files were not loaded yet. Like
+    /// `file_hash`, it is stable across runs.
+    pub fn root_hash(&self, root: VfsRoot) -> Option<u64> {
+        self.root_hashes.get(&root).copied().unwrap_or_default().finish()
+    }
+
+    /// Returns the text of `file`, reading it from disk if the file belongs to
+    /// a lazy root and was not loaded yet, or if its text was evicted.
+    ///
+    /// Loading the text of a lazy file is reported as a `ChangeFile`, and so
+    /// is reloading an evicted text which has changed on disk. If the file no
+    /// longer exists, it is removed, and `None` is returned.
+    pub fn load_text(&mut self, file: VfsFile) -> Option<Arc<String>> {
+        self.record(|| Event::LoadText(file));
+        self.reload_text(file)
+    }
+
+    /// Like `load_text`, but is not recorded, as it's a part of another call.
+    fn reload_text(&mut self, file: VfsFile) -> Option<Arc<String>> {
+        let tick = self.memory.tick();
+        self.unindex_file(file);
+        self.file_mut(file).last_accessed = tick;
+        self.index_file(file);
+        if let Some(text) = self.file(file).text() {
+            return Some(text);
+        }
+        let data = self.file(file);
+        let (root, path) = (data.root, data.path.clone());
+        let text = match self.read_file(root, &path) {
+            Some(contents) => {
+                self.restore_contents(file, contents);
+                self.file(file).text()
+            }
+            None => {
+                self.remove_file_event(root, path, file);
+                None
+            }
+        };
+        self.enforce_memory_budget();
+        text
+    }
+
+    /// Like `load_text`, but reads the file on the IO thread. The text is
+    /// reported as a `ChangeFile` once the task is handled, unless it is the
+    /// same as the evicted one.
+    ///
+    /// Only files of disk roots are read on the IO thread: use `load_text`
+    /// for archive roots.
+    pub fn request_text(&self, file: VfsFile) {
+        if self.file(file).contents.is_none() {
+            let path = self.file2path(file);
+            self.send_task(io::Task::NotifyChanged { path, origin: ChangeOrigin::Api });
+        }
+    }
+
+    /// Returns the client document version of the overlay for `file`, if the
+    /// file is overlayed and the overlay was versioned.
+    pub fn overlay_version(&self, file: VfsFile) -> Option<i64> {
+        self.file(file).overlay_version
     }
 
     pub fn n_roots(&self) -> usize {
         self.roots.len()
     }
 
+    /// Returns the current revision of the VFS, which is incremented on every
+    /// change.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Returns the revision at which `file` was last added, changed or
+    /// removed.
+    pub fn file_revision(&self, file: VfsFile) -> u64 {
+        self.file(file).last_changed
+    }
+
+    /// Returns the files which were added, changed or removed after `revision`,
+    /// in the order of their last changes.
+    pub fn changed_since(&self, revision: u64) -> impl Iterator<Item = VfsFile> + '_ {
+        let after = (Bound::Excluded((revision, VfsFile(std::u32::MAX))), Bound::Unbounded);
+        self.files_by_revision.range(after).map(|&(_, file)| file)
+    }
+
+    /// Creates a cache of the current contents of the roots, which can be
+    /// saved and passed to `with_cache` to speed up the next start.
+    ///
+    /// Overlays, virtual files and files which were not loaded yet are not
+    /// cached. As cached disk roots are only compared with the disk in the
+    /// background, the cache should be created while the VFS is up to date
+    /// with the disk.
+    pub fn cache(&self) -> VfsCache {
+        let mut cache = VfsCache::default();
+        for root in self.roots.iter() {
+            let root_path = self.roots.path(root);
+            let archive = match self.roots.kind(root) {
+                RootKind::Disk => None,
+                RootKind::Archive => match self.fs.metadata(root_path) {
+                    Ok(it) => Some(it),
+                    Err(_) => continue,
+                },
+                RootKind::Virtual | RootKind::Detached => continue,
+            };
+            let files = self.root2files[&root]
+                .iter()
+                .filter_map(|&file| {
+                    let data = self.file(file);
+                    if data.is_overlayed || data.virtual_text.is_some() {
+                        return None;
+                    }
+                    let (metadata, text) = match archive {
+                        Some(metadata) => {
+                            let text = data.contents.as_ref()?.text.to_string();
+                            let len = text.len() as u64;
+                            (Metadata { len, ..metadata }, Some(text))
+                        }
+                        // Evicted texts are still known by their hashes.
+                        None => (self.fs.metadata(&data.path.to_path(root_path)).ok()?, None),
+                    };
+                    let (path, line_endings) = (data.path.clone(), data.line_endings());
+                    Some(CachedFile { path, metadata, hash: data.hash()?, line_endings, text })
+                })
+                .collect();
+            cache.insert(root_path.to_path_buf(), CachedRoot { archive, files });
+        }
+        cache
+    }
+
+    /// Takes a cheap, immutable snapshot of the current state, which can be
+    /// sent to other threads.
+    pub fn snapshot(&self) -> VfsSnapshot {
+        VfsSnapshot {
+            roots: Arc::clone(&self.roots),
+            files: self.files.clone(),
+            root2files: self.root2files.clone(),
+            root_hashes: self.root_hashes.clone(),
+            access: Arc::clone(&self.memory.access),
+            revision: self.revision,
+        }
+    }
+
+    /// Returns `true` if `root` is a detached root created for an overlay
+    /// outside of any other root.
+    pub fn is_detached_root(&self, root: VfsRoot) -> bool {
+        self.roots.kind(root) == RootKind::Detached
+    }
+
+    /// Returns `true` if `root` was added with `add_virtual_root`.
+    pub fn is_virtual_root(&self, root: VfsRoot) -> bool {
+        self.roots.kind(root) == RootKind::Virtual
+    }
+
+    /// Returns `true` if `file` was added with `add_virtual_file`, and thus
+    /// is not backed by disk.
+    pub fn is_virtual_file(&self, file: VfsFile) -> bool {
+        self.file(file).virtual_text.is_some()
+    }
+
     pub fn load(&mut self, path: &Path) -> Option<VfsFile> {
+        self.record(|| Event::Load(path.to_path_buf()));
         if let Some((root, rel_path, file)) = self.find_root(path) {
             return if let Some(file) = file {
                 Some(file)
+            } else if self.roots.kind(root) != RootKind::Disk {
+                None
             } else {
-                let (text, line_endings) = read_to_string(path).unwrap_or_default();
-                let text = Arc::new(text);
-                let file = self.raw_add_file(
-                    root,
-                    rel_path.clone(),
-                    Arc::clone(&text),
-                    line_endings,
-                    false,
-                );
-                let change = VfsChange::AddFile { file, text, root, path: rel_path };
-                self.pending_changes.push(change);
-                Some(file)
+                let contents = read_to_string(&*self.fs, path).unwrap_or_default();
+                let file = self.add_file_event(root, rel_path, contents, false, None);
+                self.enforce_memory_budget();
+                file
             };
         }
         None
     }
 
     pub fn notify_changed(&mut self, path: PathBuf) {
-        self.worker.send(io::Task::NotifyChanged { path })
+        if self.worker.is_some() {
+            self.send_task(io::Task::NotifyChanged { path, origin: ChangeOrigin::Notification });
+            return;
+        }
+        let (fs, roots) = (Arc::clone(&self.fs), Arc::clone(&self.roots));
+        io::notify_changed_sync(&*fs, &roots, path, &mut |res| self.handle_task(VfsTask(res)));
     }
 
-    pub fn add_file_overlay(&mut self, path: &Path, mut text: String) -> Option<VfsFile> {
-        let line_endings = normalize_newlines(&mut text);
-        let (root, rel_path, file) = self.find_root(path)?;
-        if let Some(file) = file {
-            self.change_file_event(file, text, true);
-            Some(file)
-        } else {
-            self.add_file_event(root, rel_path, text, line_endings, true)
+    /// Adds a file which is not backed by disk to `root`, or replaces the
+    /// text of an existing virtual file.
+    ///
+    /// Virtual files are never read from disk: watcher events for the same
+    /// path are ignored, and removing an overlay of a virtual file restores
+    /// the virtual text.
+    ///
+    /// Returns `None` if `root` is not a virtual root.
+    pub fn add_virtual_file(
+        &mut self,
+        root: VfsRoot,
+        path: RelativePathBuf,
+        text: String,
+    ) -> Option<VfsFile> {
+        if !self.is_virtual_root(root) {
+            return None;
+        }
+        self.record(|| Event::AddVirtualFile { root, path: path.clone(), text: text.clone() });
+        let contents = FileContents::new(text);
+        let file = match self.find_file(root, &path) {
+            Some(file) => {
+                if self.file(file).is_overlayed {
+                    self.file_mut(file).virtual_text = Some(contents.text);
+                    return Some(file);
+                }
+                self.change_file_event(file, contents, false, None);
+                file
+            }
+            None => self.add_file_event(root, path, contents, false, None).unwrap(),
+        };
+        self.unindex_file(file);
+        self.file_mut(file).virtual_text = self.file(file).text();
+        self.index_file(file);
+        Some(file)
+    }
+
+    /// Removes a file added with `add_virtual_file`.
+    ///
+    /// If the file is overlayed, the overlay is kept, but the file is no
+    /// longer virtual.
+    pub fn remove_virtual_file(&mut self, file: VfsFile) {
+        self.record(|| Event::RemoveVirtualFile(file));
+        if self.file_mut(file).virtual_text.take().is_none() || self.file(file).is_overlayed {
+            return;
         }
+        let data = self.file(file);
+        let (root, path) = (data.root, data.path.clone());
+        self.remove_file_event(root, path, file);
+    }
+
+    pub fn add_file_overlay(&mut self, path: &Path, text: String) -> Option<VfsFile> {
+        self.with_origin(ChangeOrigin::Overlay, |vfs| vfs.add_overlay(path, text, None))
+    }
+
+    /// Like `add_file_overlay`, but also records the client's document
+    /// `version`, which is later used to detect stale edits.
+    pub fn add_file_overlay_with_version(
+        &mut self,
+        path: &Path,
+        text: String,
+        version: i64,
+    ) -> Option<VfsFile> {
+        self.with_origin(ChangeOrigin::Overlay, |vfs| vfs.add_overlay(path, text, Some(version)))
     }
 
     pub fn change_file_overlay<F: FnOnce(&mut String)>(&mut self, path: &Path, change: F) {
-        if let Some((_root, _path, file)) = self.find_root(path) {
-            let file = file.expect("can't change a file which wasn't added");
-            let mut text = self.file(file).text.as_ref().clone();
-            change(&mut text);
-            let _line_endings = normalize_newlines(&mut text);
+        self.with_origin(ChangeOrigin::Overlay, |vfs| vfs.change_overlay(path, None, change));
+    }
+
+    /// Like `change_file_overlay`, but tags the edit with the client's
+    /// document `version`.
+    ///
+    /// Edits are expected to arrive with strictly increasing versions. An edit
+    /// whose version is not greater than the current version of the overlay is
+    /// out of order or duplicated: it is not applied, and `false` is returned.
+    /// `false` is also returned if the file is not in the VFS, or belongs to an
+    /// archive root.
+    pub fn change_file_overlay_with_version<F: FnOnce(&mut String)>(
+        &mut self,
+        path: &Path,
+        version: i64,
+        change: F,
+    ) -> bool {
+        self.with_origin(ChangeOrigin::Overlay, |vfs| {
+            vfs.change_overlay(path, Some(version), change)
+        })
+    }
+
+    fn add_overlay(&mut self, path: &Path, text: String, version: Option<i64>) -> Option<VfsFile> {
+        self.record(|| Event::AddOverlay { path: path.to_path_buf(), text: text.clone(), version });
+        let contents = FileContents::new(text);
+        let (root, rel_path, file) = match self.find_root(path) {
+            Some(it) => it,
+            None if !self.roots.covers(path) => {
+                let (root, rel_path) = self.add_detached_root(path)?;
+                (root, rel_path, None)
+            }
+            None => return None,
+        };
+        if self.roots.kind(root) == RootKind::Archive {
+            // Archives are immutable.
+            return file;
+        }
+        let file = match file {
+            Some(file) => {
+                self.change_file_event(file, contents, true, version);
+                file
+            }
+            None => self.add_file_event(root, rel_path, contents, true, version)?,
+        };
+        self.enforce_memory_budget();
+        Some(file)
+    }
 
-            self.change_file_event(file, text, true);
+    fn change_overlay<F: FnOnce(&mut String)>(
+        &mut self,
+        path: &Path,
+        version: Option<i64>,
+        change: F,
+    ) -> bool {
+        if let Some((root, rel_path, Some(file))) = self.find_root(path) {
+            if self.roots.kind(root) == RootKind::Archive {
+                return false;
+            }
+            if let (Some(old), Some(new)) = (self.file(file).overlay_version, version) {
+                if new <= old {
+                    log::warn!(
+                        "ignoring stale edit of {}: version {} <= {}",
+                        path.display(),
+                        new,
+                        old
+                    );
+                    return false;
+                }
+            }
+            // The read is recorded after the edit, so that it is replayed
+            // before it, like the reads of the other calls.
+            self.hold_reads();
+            let mut text = match self.file(file).text() {
+                Some(text) => String::clone(&text),
+                // The text is read silently, as only the edited text is
+                // reported.
+                None => match self.read_file(root, &rel_path) {
+                    Some(contents) => String::clone(&contents.text),
+                    None => {
+                        // The replayed read fails as well, so the text of the
+                        // edit doesn't matter.
+                        self.record(|| Event::ChangeOverlay {
+                            path: path.to_path_buf(),
+                            text: String::new(),
+                            version,
+                        });
+                        self.remove_file_event(root, rel_path, file);
+                        return false;
+                    }
+                },
+            };
+            change(&mut text);
+            self.record(|| Event::ChangeOverlay {
+                path: path.to_path_buf(),
+                text: text.clone(),
+                version,
+            });
+            self.change_file_event(file, FileContents::new(text), true, version);
+            self.enforce_memory_budget();
+            return true;
         }
+        false
     }
 
+    /// Removes the overlay of the file at `path`.
+    ///
+    /// In a detached root, the file and the root are removed with the overlay.
+    /// The id of the root is never reused for another root. Otherwise,
+    /// the file gets back its virtual or disk text, and the change is
+    /// reported as `ChangeOrigin::Reconcile`.
     pub fn remove_file_overlay(&mut self, path: &Path) -> Option<VfsFile> {
+        self.record(|| Event::RemoveOverlay { path: path.to_path_buf() });
         let (root, rel_path, file) = self.find_root(path)?;
         let file = file.expect("can't remove a file which wasn't added");
-        let full_path = rel_path.to_path(&self.roots.path(root));
-        match fs::read_to_string(&full_path) {
-            Ok(mut text) => {
-                let _line_endings = normalize_newlines(&mut text);
-                self.change_file_event(file, text, false);
+        if self.roots.kind(root) == RootKind::Archive {
+            return Some(file);
+        }
+        if self.file(file).virtual_text.is_none() && self.roots.kind(root) != RootKind::Disk {
+            self.with_origin(ChangeOrigin::Overlay, |vfs| {
+                vfs.remove_file_event(root, rel_path, file)
+            });
+            if self.roots.kind(root) == RootKind::Detached {
+                Arc::make_mut(&mut self.roots).remove_detached(root);
+                self.root2files.remove(&root);
+                self.root_hashes.remove(&root);
             }
-            Err(_) => self.remove_file_event(root, rel_path, file),
+            return Some(file);
         }
+        self.with_origin(ChangeOrigin::Reconcile, |vfs| {
+            if let Some(text) = &vfs.file(file).virtual_text {
+                let contents = FileContents::new(String::clone(text));
+                vfs.change_file_event(file, contents, false, None);
+                return;
+            }
+            let full_path = rel_path.to_path(vfs.roots.path(root));
+            match vfs.fs.read_to_string(&full_path) {
+                Ok(text) => {
+                    vfs.change_file_event(file, FileContents::new(text), false, None);
+                    vfs.enforce_memory_budget();
+                }
+                Err(_) => vfs.remove_file_event(root, rel_path, file),
+            }
+        });
         Some(file)
     }
 
-    pub fn commit_changes(&mut self) -> Vec<VfsChange> {
+    /// Returns the changes since the previous call.
+    pub fn commit_changes(&mut self) -> ChangeSet {
         // FIXME: ideally we should compact changes here, such that we send at
         // most one event per VfsFile.
-        mem::replace(&mut self.pending_changes, Vec::new())
+        let changes = mem::take(&mut self.pending_changes);
+        let revisions = self.committed_revision..self.revision;
+        self.committed_revision = self.revision;
+        ChangeSet::new(changes, revisions, |file| self.file(file).root)
     }
 
     pub fn handle_task(&mut self, task: VfsTask) {
-        match task.0 {
+        self.record(|| Event::Result(task.0.clone()));
+        self.apply_task(task.0);
+        self.enforce_memory_budget();
+    }
+
+    fn apply_task(&mut self, task: TaskResult) {
+        match task {
+            TaskResult::LoadRootChunk { root, files } => {
+                let reported = &self.loading_roots[&root];
+                // Files which were open in the editor in the meantime are
+                // reported with the last chunk, see below.
+                let existing = self.root2files[&root]
+                    .iter()
+                    .filter(|file| !reported.contains(file))
+                    .map(|&file| self.file(file).path.clone())
+                    .collect::<FxHashSet<_>>();
+                let cur_files = files
+                    .into_iter()
+                    .filter(|(path, _)| !existing.contains(path))
+                    .map(|(path, contents)| self.raw_add_file(root, path, contents, false, None))
+                    .collect::<Vec<_>>();
+                self.loading_roots.get_mut(&root).unwrap().extend(cur_files.iter().copied());
+                let (files, unloaded) = self.split_unloaded(cur_files);
+                self.push_change(VfsChange::AddRootChunk {
+                    root,
+                    files,
+                    unloaded,
+                    origin: self.origin,
+                    time: self.now(),
+                });
+            }
             TaskResult::BulkLoadRoot { root, files } => {
                 let mut cur_files = Vec::new();
+                // Files of the previous chunks were already reported.
+                let reported = self.loading_roots.remove(&root).unwrap_or_default();
                 // While we were scanning the root in the background, a file might have
                 // been open in the editor, so we need to account for that.
-                let existing = self.root2files[&root]
+                let mut existing = self.root2files[&root]
                     .iter()
+                    .filter(|file| !reported.contains(file))
                     .map(|&file| (self.file(file).path.clone(), file))
                     .collect::<FxHashMap<_, _>>();
-                for (path, text, line_endings) in files {
-                    if let Some(&file) = existing.get(&path) {
-                        let text = Arc::clone(&self.file(file).text);
-                        cur_files.push((file, path, text));
+                for (path, contents) in files {
+                    if let Some(file) = existing.remove(&path) {
+                        cur_files.push(file);
                         continue;
                     }
-                    let text = Arc::new(text);
-                    let file = self.raw_add_file(
-                        root,
-                        path.clone(),
-                        Arc::clone(&text),
-                        line_endings,
-                        false,
-                    );
-                    cur_files.push((file, path, text));
+                    cur_files.push(self.raw_add_file(root, path, contents, false, None));
                 }
-
-                let change = VfsChange::AddRoot { root, files: cur_files };
-                self.pending_changes.push(change);
+                // Files which are not on disk, but were added to the root
+                // (overlays for new files or files moved from another root).
+                cur_files.extend(existing.into_iter().map(|(_, file)| file));
+                self.push_add_root(root, cur_files);
             }
-            TaskResult::SingleFile { root, path, text, line_endings } => {
-                let existing_file = self.find_file(root, &path);
-                if existing_file.map(|file| self.file(file).is_overlayed) == Some(true) {
-                    return;
-                }
-                match (existing_file, text) {
-                    (Some(file), None) => {
-                        self.remove_file_event(root, path, file);
-                    }
-                    (None, Some(text)) => {
-                        self.add_file_event(root, path, text, line_endings, false);
-                    }
-                    (Some(file), Some(text)) => {
-                        if *self.file(file).text != text {
-                            self.change_file_event(file, text, false);
-                        }
+            TaskResult::ReloadRoot { root, files, origin, time } => {
+                self.with_origin(origin, |vfs| {
+                    vfs.at_time(time, |vfs| vfs.reload_root(root, files))
+                });
+            }
+            TaskResult::SingleFile { root, path, contents, origin, time } => {
+                self.with_origin(origin, |vfs| {
+                    vfs.at_time(time, |vfs| vfs.apply_single_file(root, path, contents))
+                });
+            }
+            TaskResult::Progress(_) => (),
+        }
+    }
+
+    fn reload_root(&mut self, root: VfsRoot, files: Vec<(RelativePathBuf, FileContents)>) {
+        let mut existing = self.root2files[&root]
+            .iter()
+            .map(|&file| (self.file(file).path.clone(), file))
+            .collect::<FxHashMap<_, _>>();
+        for (path, contents) in files {
+            match existing.remove(&path) {
+                Some(file) => {
+                    let data = self.file(file);
+                    if !data.is_overlayed
+                        && data.virtual_text.is_none()
+                        && !self.has_contents(file, &contents)
+                    {
+                        self.change_file_event(file, contents, false, None);
                     }
-                    (None, None) => (),
+                }
+                None => {
+                    self.add_file_event(root, path, contents, false, None);
                 }
             }
         }
+        for (path, file) in existing {
+            let data = self.file(file);
+            if !data.is_overlayed && data.virtual_text.is_none() {
+                self.remove_file_event(root, path, file);
+            }
+        }
+    }
+
+    fn apply_single_file(
+        &mut self,
+        root: VfsRoot,
+        path: RelativePathBuf,
+        contents: Option<FileContents>,
+    ) {
+        if self.roots.kind(root) != RootKind::Disk {
+            return;
+        }
+        let existing_file = self.find_file(root, &path);
+        if let Some(file) = existing_file {
+            let data = self.file(file);
+            if data.is_overlayed || data.virtual_text.is_some() {
+                return;
+            }
+        }
+        match (existing_file, contents) {
+            (Some(file), None) => {
+                self.remove_file_event(root, path, file);
+            }
+            (None, Some(contents)) => {
+                self.add_file_event(root, path, contents, false, None);
+            }
+            (Some(file), Some(contents)) => {
+                if self.file(file).contents.is_none() {
+                    self.restore_contents(file, contents);
+                } else if !self.has_contents(file, &contents) {
+                    self.change_file_event(file, contents, false, None);
+                }
+            }
+            (None, None) => (),
+        }
+    }
+
+    /// Runs `f` with the changes it makes attributed to `origin`.
+    fn with_origin<T>(&mut self, origin: ChangeOrigin, f: impl FnOnce(&mut Vfs) -> T) -> T {
+        let prev = mem::replace(&mut self.origin, origin);
+        let res = f(self);
+        self.origin = prev;
+        res
+    }
+
+    /// Reports the changes made by `f` as happened at `time`.
+    fn at_time<T>(&mut self, time: SystemTime, f: impl FnOnce(&mut Vfs) -> T) -> T {
+        let prev = self.time.replace(time);
+        let res = f(self);
+        self.time = prev;
+        res
+    }
+
+    /// Time of the changes which are being applied, see `at_time`.
+    fn now(&self) -> SystemTime {
+        self.time.unwrap_or_else(SystemTime::now)
     }
 
     // *_event calls change the state of VFS and push a change onto pending
@@ -341,26 +1308,129 @@ impl Vfs {
         &mut self,
         root: VfsRoot,
         path: RelativePathBuf,
-        text: String,
-        line_endings: LineEndings,
+        contents: FileContents,
         is_overlay: bool,
+        version: Option<i64>,
     ) -> Option<VfsFile> {
-        let text = Arc::new(text);
-        let file =
-            self.raw_add_file(root, path.clone(), Arc::clone(&text), line_endings, is_overlay);
-        self.pending_changes.push(VfsChange::AddFile { file, root, path, text });
+        let file = self.raw_add_file(root, path.clone(), Some(contents), is_overlay, version);
+        // The text is interned when added, so take it from the file.
+        let text = self.file(file).text().unwrap();
+        self.push_change(VfsChange::AddFile {
+            file,
+            root,
+            path,
+            text,
+            origin: self.origin,
+            time: self.now(),
+        });
         Some(file)
     }
 
-    fn change_file_event(&mut self, file: VfsFile, text: String, is_overlay: bool) {
-        let text = Arc::new(text);
-        self.raw_change_file(file, text.clone(), is_overlay);
-        self.pending_changes.push(VfsChange::ChangeFile { file, text });
+    fn change_file_event(
+        &mut self,
+        file: VfsFile,
+        contents: FileContents,
+        is_overlay: bool,
+        version: Option<i64>,
+    ) {
+        self.raw_change_file(file, contents, is_overlay, version);
+        let text = self.file(file).text().unwrap();
+        self.push_change(VfsChange::ChangeFile {
+            file,
+            text,
+            version,
+            origin: self.origin,
+            time: self.now(),
+        });
     }
 
     fn remove_file_event(&mut self, root: VfsRoot, path: RelativePathBuf, file: VfsFile) {
         self.raw_remove_file(file);
-        self.pending_changes.push(VfsChange::RemoveFile { root, path, file });
+        self.push_change(VfsChange::RemoveFile {
+            root,
+            path,
+            file,
+            origin: self.origin,
+            time: self.now(),
+        });
+    }
+
+    /// Moves `file` to a newly added `root`. The file is added to the new root
+    /// silently, as it will be reported as a part of the root's `AddRoot`.
+    fn move_file_event(&mut self, file: VfsFile, root: VfsRoot, path: RelativePathBuf) {
+        self.unindex_file(file);
+        let data = self.file_mut(file);
+        let old_root = mem::replace(&mut data.root, root);
+        let old_path = mem::replace(&mut data.path, path);
+        Arc::make_mut(self.root2files.get_mut(&old_root).unwrap()).remove(&file);
+        Arc::make_mut(self.root2files.get_mut(&root).unwrap()).insert(file);
+        self.index_file(file);
+        self.push_change(VfsChange::RemoveFile {
+            root: old_root,
+            path: old_path,
+            file,
+            origin: self.origin,
+            time: self.now(),
+        });
+        if self.roots.kind(old_root) == RootKind::Detached {
+            Arc::make_mut(&mut self.roots).remove_detached(old_root);
+            self.root2files.remove(&old_root);
+            self.root_hashes.remove(&old_root);
+        }
+    }
+
+    /// Installs `roots`, which contain a new `root`, and moves the files
+    /// which belong to the new root to it.
+    fn insert_root(&mut self, roots: Roots, root: VfsRoot) {
+        self.roots = Arc::new(roots);
+        self.root2files.insert(root, Default::default());
+        self.send_task(io::Task::UpdateRoots { roots: Arc::clone(&self.roots) });
+
+        let files = self.root2files.values().flat_map(|it| it.iter().copied()).collect::<Vec<_>>();
+        for file in files {
+            let path = self.file2path(file);
+            if let Some((new_root, rel_path)) = self.roots.find(&path, FileType::File) {
+                if new_root == root {
+                    self.move_file_event(file, root, rel_path);
+                }
+            }
+        }
+    }
+
+    fn add_detached_root(&mut self, path: &Path) -> Option<(VfsRoot, RelativePathBuf)> {
+        let (root, rel_path) = Arc::make_mut(&mut self.roots).add_detached(path)?;
+        self.root2files.insert(root, Default::default());
+        self.push_change(VfsChange::AddRoot {
+            root,
+            files: Vec::new(),
+            unloaded: Vec::new(),
+            origin: self.origin,
+            time: self.now(),
+        });
+        Some((root, rel_path))
+    }
+
+    fn push_add_root(&mut self, root: VfsRoot, files: Vec<VfsFile>) {
+        let (files, unloaded) = self.split_unloaded(files);
+        self.push_change(VfsChange::AddRoot {
+            root,
+            files,
+            unloaded,
+            origin: self.origin,
+            time: self.now(),
+        });
+    }
+
+    fn split_unloaded(&self, files: Vec<VfsFile>) -> SplitFiles {
+        let (mut loaded, mut unloaded) = (Vec::new(), Vec::new());
+        for file in files {
+            let path = self.file(file).path.clone();
+            match self.file(file).text() {
+                Some(text) => loaded.push((file, path, text)),
+                None => unloaded.push((file, path)),
+            }
+        }
+        (loaded, unloaded)
     }
 
     // raw_* calls change the state of VFS, but **do not** emit events.
@@ -369,56 +1439,238 @@ impl Vfs {
         &mut self,
         root: VfsRoot,
         path: RelativePathBuf,
-        text: Arc<String>,
-        line_endings: LineEndings,
+        mut contents: Option<FileContents>,
         is_overlayed: bool,
+        overlay_version: Option<i64>,
     ) -> VfsFile {
-        let data = VfsFileData { root, path, text, line_endings, is_overlayed };
+        if let Some(contents) = &mut contents {
+            self.memory.intern(contents);
+        }
+        let data = VfsFileData {
+            root,
+            path,
+            contents,
+            evicted: None,
+            last_accessed: self.memory.tick(),
+            is_overlayed,
+            overlay_version,
+            virtual_text: None,
+            last_changed: self.revision,
+        };
         let file = VfsFile(self.files.len() as u32);
         self.files.push(data);
-        self.root2files.get_mut(&root).unwrap().insert(file);
+        self.memory.access.add_file();
+        self.files_by_revision.insert((self.revision, file));
+        Arc::make_mut(self.root2files.get_mut(&root).unwrap()).insert(file);
+        self.index_file(file);
         file
     }
 
-    fn raw_change_file(&mut self, file: VfsFile, new_text: Arc<String>, is_overlayed: bool) {
-        let mut file_data = &mut self.file_mut(file);
-        file_data.text = new_text;
+    fn raw_change_file(
+        &mut self,
+        file: VfsFile,
+        contents: FileContents,
+        is_overlayed: bool,
+        overlay_version: Option<i64>,
+    ) {
+        self.set_contents(file, contents);
+        self.unindex_file(file);
+        let file_data = &mut self.file_mut(file);
+        // An unversioned edit keeps the version of the overlay, so that later
+        // versioned edits are still checked against it.
+        file_data.overlay_version = match (is_overlayed, file_data.is_overlayed) {
+            (false, _) => None,
+            (true, true) => overlay_version.or(file_data.overlay_version),
+            (true, false) => overlay_version,
+        };
         file_data.is_overlayed = is_overlayed;
+        self.index_file(file);
     }
 
     fn raw_remove_file(&mut self, file: VfsFile) {
         // FIXME: use arena with removal
-        self.file_mut(file).text = Default::default();
+        self.set_contents(file, FileContents::default());
+        self.unindex_file(file);
         self.file_mut(file).path = Default::default();
+        self.file_mut(file).is_overlayed = false;
+        self.file_mut(file).overlay_version = None;
+        self.file_mut(file).virtual_text = None;
         let root = self.file(file).root;
-        let removed = self.root2files.get_mut(&root).unwrap().remove(&file);
+        let removed = Arc::make_mut(self.root2files.get_mut(&root).unwrap()).remove(&file);
         assert!(removed);
     }
 
     fn find_root(&self, path: &Path) -> Option<(VfsRoot, RelativePathBuf, Option<VfsFile>)> {
-        let (root, path) = self.roots.find(&path, FileType::File)?;
+        let (root, path) =
+            self.roots.find(path, FileType::File).or_else(|| self.roots.find_detached(path))?;
         let file = self.find_file(root, &path);
         Some((root, path, file))
     }
 
+    fn set_contents(&mut self, file: VfsFile, mut contents: FileContents) {
+        let tick = self.memory.tick();
+        self.memory.intern(&mut contents);
+        self.unindex_file(file);
+        let data = self.file_mut(file);
+        let old = data.contents.replace(contents);
+        data.evicted = None;
+        data.last_accessed = tick;
+        self.index_file(file);
+        if let Some(old) = old {
+            self.memory.release(&old);
+        }
+    }
+
+    /// Adds `file` to the hash of its root and, if its text can be evicted,
+    /// to the eviction order.
+    fn index_file(&mut self, file: VfsFile) {
+        let data = self.files.get(file.0 as usize);
+        let root_hash = self.root_hashes.entry(data.root).or_default();
+        root_hash.insert(&data.path, data.hash());
+        let evictable = self.roots.kind(data.root) == RootKind::Disk
+            && data.contents.is_some()
+            && !data.is_overlayed
+            && data.virtual_text.is_none();
+        if evictable {
+            self.memory.lru.insert(data.last_accessed, file);
+        }
+    }
+
+    /// Removes `file` from the indexes of `index_file`, before it changes.
+    fn unindex_file(&mut self, file: VfsFile) {
+        let data = self.files.get(file.0 as usize);
+        let root_hash = self.root_hashes.entry(data.root).or_default();
+        root_hash.remove(&data.path, data.hash());
+        // Ticks are unique, so the entry can only be this file's.
+        self.memory.lru.remove(&data.last_accessed);
+    }
+
+    /// Sets the contents of a file which were not loaded or were evicted.
+    ///
+    /// A change is reported unless the contents are the same as the evicted
+    /// ones.
+    fn restore_contents(&mut self, file: VfsFile, contents: FileContents) {
+        match self.file(file).evicted {
+            Some((hash, _)) => {
+                self.memory.reloads += 1;
+                if hash == contents.hash {
+                    self.set_contents(file, contents);
+                } else {
+                    self.change_file_event(file, contents, false, None);
+                }
+            }
+            None => self.change_file_event(file, contents, false, None),
+        }
+    }
+
+    /// Reads the contents of a file from the root's disk directory or archive.
+    fn read_file(&self, root: VfsRoot, path: &RelativePath) -> Option<FileContents> {
+        match self.roots.kind(root) {
+            RootKind::Disk => read_to_string(&*self.fs, &path.to_path(self.roots.path(root))),
+            RootKind::Archive => io::load_archive(&*self.fs, &self.roots, root)
+                .into_iter()
+                .find_map(|(it, contents)| if it == path { Some(contents) } else { None }),
+            RootKind::Virtual | RootKind::Detached => None,
+        }
+    }
+
+    /// Checks if `file` already has the `contents`, comparing the hashes
+    /// first to avoid comparing the texts in the common case. For evicted
+    /// contents, only the hashes can be compared.
+    fn has_contents(&self, file: VfsFile, contents: &FileContents) -> bool {
+        let data = self.file(file);
+        match (&data.contents, data.evicted) {
+            (Some(it), _) => it.hash == contents.hash && it.text == contents.text,
+            (None, Some((hash, _))) => hash == contents.hash,
+            (None, None) => false,
+        }
+    }
+
     fn find_file(&self, root: VfsRoot, path: &RelativePath) -> Option<VfsFile> {
-        self.root2files[&root].iter().map(|&it| it).find(|&file| self.file(file).path == path)
+        self.root2files[&root].iter().copied().find(|&file| self.file(file).path == path)
     }
 
     fn file(&self, file: VfsFile) -> &VfsFileData {
-        &self.files[file.0 as usize]
+        self.files.get(file.0 as usize)
     }
 
     fn file_mut(&mut self, file: VfsFile) -> &mut VfsFileData {
-        &mut self.files[file.0 as usize]
+        self.files.get_mut(file.0 as usize)
+    }
+
+    fn set_last_changed(&mut self, file: VfsFile, revision: u64) {
+        let old = mem::replace(&mut self.file_mut(file).last_changed, revision);
+        self.files_by_revision.remove(&(old, file));
+        self.files_by_revision.insert((revision, file));
+    }
+
+    fn push_change(&mut self, change: VfsChange) {
+        self.revision += 1;
+        let revision = self.revision;
+        match &change {
+            VfsChange::AddRoot { files, unloaded, .. }
+            | VfsChange::AddRootChunk { files, unloaded, .. } => {
+                let files = files.iter().map(|it| it.0).chain(unloaded.iter().map(|it| it.0));
+                for file in files.collect::<Vec<_>>() {
+                    self.set_last_changed(file, revision);
+                }
+            }
+            VfsChange::AddFile { file, .. }
+            | VfsChange::RemoveFile { file, .. }
+            | VfsChange::ChangeFile { file, .. } => self.set_last_changed(*file, revision),
+        }
+        self.log_change(&change);
+        self.pending_changes.push(change);
     }
 }
 
-fn read_to_string(path: &Path) -> Option<(String, LineEndings)> {
-    let mut text =
-        fs::read_to_string(&path).map_err(|e| log::warn!("failed to read file {}", e)).ok()?;
-    let line_endings = normalize_newlines(&mut text);
-    Some((text, line_endings))
+impl VfsFileData {
+    fn hash(&self) -> Option<u64> {
+        match (&self.contents, self.evicted) {
+            (Some(contents), _) => Some(contents.hash),
+            (None, evicted) => evicted.map(|(hash, _)| hash),
+        }
+    }
+
+    fn text(&self) -> Option<Arc<String>> {
+        self.contents.as_ref().map(|it| Arc::clone(&it.text))
+    }
+
+    fn line_endings(&self) -> LineEndings {
+        match (&self.contents, self.evicted) {
+            (Some(contents), _) => contents.line_endings,
+            (None, evicted) => evicted.map(|(_, it)| it).unwrap_or_default(),
+        }
+    }
+}
+
+/// Text of a file with normalized newlines, together with its hash.
+#[derive(Debug, Clone)]
+pub(crate) struct FileContents {
+    text: Arc<String>,
+    line_endings: LineEndings,
+    hash: u64,
+}
+
+impl Default for FileContents {
+    fn default() -> FileContents {
+        FileContents::new(String::new())
+    }
+}
+
+impl FileContents {
+    /// Normalizes newlines of `text` and computes the hash. This is done on
+    /// the IO thread for the files read from disk.
+    pub(crate) fn new(mut text: String) -> FileContents {
+        let line_endings = normalize_newlines(&mut text);
+        let hash = hash::hash_text(&text);
+        FileContents { text: Arc::new(text), line_endings, hash }
+    }
+}
+
+fn read_to_string(fs: &dyn FileSystem, path: &Path) -> Option<FileContents> {
+    let text = fs.read_to_string(path).map_err(|e| log::warn!("failed to read file {}", e)).ok()?;
+    Some(FileContents::new(text))
 }
 
 /// Replaces `\r\n` with `\n` in-place in `src`.
@@ -432,7 +1684,7 @@ pub fn normalize_newlines(src: &mut String) -> LineEndings {
     // directly, let's rather steal the contents of `src`. This makes the code
     // safe even if a panic occurs.
 
-    let mut buf = std::mem::replace(src, String::new()).into_bytes();
+    let mut buf = std::mem::take(src).into_bytes();
     let mut gap_len = 0;
     let mut tail = buf.as_mut_slice();
     loop {
@@ -477,7 +1729,7 @@ pub fn normalize_newlines(src: &mut String) -> LineEndings {
 #[cfg(test)]
 mod tests {
     use super::*;
-    struct NoopFilter;
+    pub(crate) struct NoopFilter;
 
     impl Filter for NoopFilter {
         fn include_dir(&self, _: &RelativePath) -> bool {
@@ -498,4 +1750,246 @@ mod tests {
         let (_, roots) = Vfs::new(entries, Box::new(|_task| ()), Watch(true));
         assert_eq!(roots.len(), 2);
     }
+
+    #[test]
+    fn vfs_rejects_stale_overlay_edits() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let path = Path::new("/foo/lib.rs");
+        let file = vfs.add_file_overlay_with_version(path, "a".to_string(), 1).unwrap();
+        assert_eq!(vfs.overlay_version(file), Some(1));
+
+        assert!(vfs.change_file_overlay_with_version(path, 3, |text| text.push('b')));
+        assert!(!vfs.change_file_overlay_with_version(path, 2, |text| text.push('c')));
+        assert!(!vfs.change_file_overlay_with_version(path, 3, |text| text.push('d')));
+        assert_eq!(vfs.overlay_version(file), Some(3));
+
+        let changes = vfs.commit_changes();
+        assert_eq!(changes.len(), 2);
+        match &changes[1] {
+            VfsChange::ChangeFile { text, version, .. } => {
+                assert_eq!(text.as_str(), "ab");
+                assert_eq!(*version, Some(3));
+            }
+            change => panic!("unexpected change {:?}", change),
+        }
+
+        vfs.remove_file_overlay(path);
+        assert_eq!(vfs.overlay_version(file), None);
+    }
+
+    #[test]
+    fn vfs_keeps_overlay_version_on_unversioned_edits() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let path = Path::new("/foo/lib.rs");
+        let file = vfs.add_file_overlay_with_version(path, "a".to_string(), 2).unwrap();
+        vfs.change_file_overlay(path, |text| text.push('b'));
+        assert_eq!(vfs.overlay_version(file), Some(2));
+        assert!(!vfs.change_file_overlay_with_version(path, 1, |text| text.push('c')));
+        assert_eq!(vfs.file_text(file).unwrap().as_str(), "ab");
+
+        // edits of files which are not in the VFS are not applied
+        assert!(!vfs
+            .change_file_overlay_with_version(Path::new("/bar/lib.rs"), 3, |text| text.push('d')));
+        assert!(!vfs
+            .change_file_overlay_with_version(Path::new("/foo/new.rs"), 3, |text| text.push('d')));
+        assert_eq!(vfs.path2file(Path::new("/foo/new.rs")), None);
+    }
+
+    #[test]
+    fn vfs_virtual_files() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let out_dir = vfs.add_virtual_root("/out".into());
+        assert!(vfs.is_virtual_root(out_dir));
+        let file = vfs.add_virtual_file(out_dir, "gen.rs".into(), "gen".to_string()).unwrap();
+        assert!(vfs.is_virtual_file(file));
+        assert_eq!(vfs.path2file(Path::new("/out/gen.rs")), Some(file));
+        assert_eq!(vfs.load(Path::new("/out/missing.rs")), None);
+        match vfs.commit_changes().as_slice() {
+            [VfsChange::AddRoot { files, .. }, VfsChange::AddFile { text, .. }] => {
+                assert!(files.is_empty());
+                assert_eq!(text.as_str(), "gen");
+            }
+            changes => panic!("unexpected changes {:?}", changes),
+        }
+
+        vfs.add_file_overlay(Path::new("/out/gen.rs"), "edited".to_string());
+        vfs.remove_file_overlay(Path::new("/out/gen.rs"));
+        let changes = vfs.commit_changes();
+        assert_eq!(changes.len(), 2);
+        match &changes[1] {
+            VfsChange::ChangeFile { text, .. } => assert_eq!(text.as_str(), "gen"),
+            change => panic!("unexpected change {:?}", change),
+        }
+
+        vfs.remove_virtual_file(file);
+        assert_eq!(vfs.path2file(Path::new("/out/gen.rs")), None);
+
+        // only virtual roots can have virtual files
+        let foo = vfs.path2root(Path::new("/foo")).unwrap();
+        assert_eq!(vfs.add_virtual_file(foo, "gen.rs".into(), "gen".to_string()), None);
+        let detached = vfs.add_file_overlay(Path::new("/bar/scratch.rs"), String::new()).unwrap();
+        let detached = vfs.file2root(detached);
+        assert_eq!(vfs.add_virtual_file(detached, "gen.rs".into(), "gen".to_string()), None);
+        assert_eq!(vfs.path2file(Path::new("/foo/gen.rs")), None);
+    }
+
+    #[test]
+    fn vfs_moves_detached_files_to_new_roots() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (mut vfs, _) = Vfs::new(
+            vec![entry("/foo")],
+            Box::new(move |task| sender.send(task).unwrap()),
+            Watch(false),
+        );
+        let path = Path::new("/bar/scratch.rs");
+        let file = vfs.add_file_overlay(path, "scratch".to_string()).unwrap();
+        assert_eq!(vfs.path2file(path), Some(file));
+        assert_eq!(vfs.file2path(file), path);
+        let detached = match vfs.commit_changes().as_slice() {
+            [VfsChange::AddRoot { root, files, .. }, VfsChange::AddFile { root: file_root, .. }] => {
+                assert!(files.is_empty());
+                assert_eq!(root, file_root);
+                *root
+            }
+            changes => panic!("unexpected changes {:?}", changes),
+        };
+        assert!(vfs.is_detached_root(detached));
+
+        let bar = vfs.add_root(entry("/bar"));
+        assert_ne!(bar, detached);
+        while !vfs.all_roots_loaded() {
+            vfs.handle_task(receiver.recv().unwrap());
+        }
+        let changes = vfs.commit_changes();
+        assert!(changes.iter().any(
+            |change| matches!(change, VfsChange::RemoveFile { root, .. } if *root == detached)
+        ));
+        match changes.iter().find_map(|change| match change {
+            VfsChange::AddRoot { root, files, .. } if *root == bar => Some(files.as_slice()),
+            _ => None,
+        }) {
+            Some([(_, path, text)]) => {
+                assert_eq!(path, "scratch.rs");
+                assert_eq!(text.as_str(), "scratch");
+            }
+            files => panic!("unexpected files {:?}", files),
+        }
+
+        assert_eq!(vfs.path2file(path), Some(file));
+        assert_eq!(vfs.file2path(file), path);
+        assert_eq!(vfs.path2root(path.parent().unwrap()), Some(bar));
+        assert!(!vfs.is_detached_root(bar));
+        // the detached root is gone
+        assert_eq!(vfs.n_roots(), 2);
+    }
+
+    #[test]
+    fn vfs_removes_detached_roots_with_overlays() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let path = Path::new("/bar/scratch.rs");
+        let file = vfs.add_file_overlay(path, "scratch".to_string()).unwrap();
+        let detached = vfs.file2root(file);
+        assert_eq!(vfs.n_roots(), 2);
+
+        vfs.remove_file_overlay(path);
+        assert_eq!(vfs.n_roots(), 1);
+        assert_eq!(vfs.path2file(path), None);
+
+        let file = vfs.add_file_overlay(Path::new("/baz/scratch.rs"), "baz".to_string()).unwrap();
+        assert_ne!(vfs.file2root(file), detached);
+        assert_eq!(vfs.n_roots(), 2);
+        let root = vfs.add_virtual_root("/out".into());
+        assert_ne!(root, detached);
+    }
+
+    #[test]
+    fn vfs_snapshots_are_immutable() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<VfsSnapshot>();
+
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let path = Path::new("/foo/lib.rs");
+        let file = vfs.add_file_overlay(path, "old".to_string()).unwrap();
+        let snapshot = vfs.snapshot();
+        assert_eq!(snapshot.revision(), vfs.revision());
+
+        vfs.change_file_overlay(path, |text| *text = "new".to_string());
+        vfs.add_file_overlay(Path::new("/foo/main.rs"), "main".to_string());
+        assert!(vfs.revision() > snapshot.revision());
+        assert_eq!(vfs.file_text(file).unwrap().as_str(), "new");
+
+        let handle = std::thread::spawn(move || {
+            assert_eq!(snapshot.path2file(path), Some(file));
+            assert_eq!(snapshot.path2file(Path::new("/foo/main.rs")), None);
+            assert_eq!(snapshot.root_files(snapshot.file2root(file)).len(), 1);
+            snapshot.file_text(file)
+        });
+        assert_eq!(handle.join().unwrap().unwrap().as_str(), "old");
+    }
+
+    #[test]
+    fn vfs_tracks_file_revisions() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let lib = vfs.add_file_overlay(Path::new("/foo/lib.rs"), "lib".to_string()).unwrap();
+        let main = vfs.add_file_overlay(Path::new("/foo/main.rs"), "main".to_string()).unwrap();
+        let start = vfs.revision();
+        assert_eq!(vfs.file_revision(main), start);
+        assert_eq!(vfs.changed_since(start).count(), 0);
+
+        vfs.change_file_overlay(Path::new("/foo/lib.rs"), |text| text.push('!'));
+        assert_eq!(vfs.changed_since(start).collect::<Vec<_>>(), vec![lib]);
+        assert!(vfs.file_revision(lib) > vfs.file_revision(main));
+
+        let after_change = vfs.revision();
+        vfs.remove_file_overlay(Path::new("/foo/main.rs"));
+        assert_eq!(vfs.changed_since(after_change).collect::<Vec<_>>(), vec![main]);
+        assert_eq!(vfs.changed_since(0).count(), 2);
+    }
+
+    #[test]
+    fn interner_shares_texts_while_they_are_used() {
+        let interner = memory::TextInterner::default();
+        let mut first = FileContents::new("fn main() {}".to_string());
+        let mut second = FileContents::new("fn main() {}".to_string());
+        interner.intern(&mut first);
+        interner.intern(&mut second);
+        assert!(Arc::ptr_eq(&first.text, &second.text));
+
+        // freed texts are not kept alive
+        drop((first, second));
+        let mut third = FileContents::new("fn main() {}".to_string());
+        interner.intern(&mut third);
+        assert_eq!(Arc::strong_count(&third.text), 1);
+    }
+
+    #[test]
+    fn vfs_hashes_files_and_roots() {
+        let (mut vfs, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        let root = vfs.path2root(Path::new("/foo")).unwrap();
+        let empty_root_hash = vfs.root_hash(root);
+        let lib = vfs.add_file_overlay(Path::new("/foo/lib.rs"), "lib".to_string()).unwrap();
+        let main = vfs.add_file_overlay(Path::new("/foo/main.rs"), "lib\r\n".to_string());
+        // hashes are stable across runs
+        assert_eq!(vfs.file_hash(lib), Some(0x124a_6619_1daa_d26c));
+        assert_ne!(vfs.file_hash(main.unwrap()), vfs.file_hash(lib));
+        assert_ne!(vfs.root_hash(root), empty_root_hash);
+
+        let root_hash = vfs.root_hash(root);
+        vfs.change_file_overlay(Path::new("/foo/lib.rs"), |text| text.push('!'));
+        assert_ne!(vfs.root_hash(root), root_hash);
+        vfs.change_file_overlay(Path::new("/foo/lib.rs"), |text| {
+            text.pop();
+        });
+        assert_eq!(vfs.root_hash(root), root_hash);
+        assert_eq!(vfs.snapshot().root_hash(root), root_hash);
+
+        // the hash doesn't depend on the order the files were added in
+        let (mut other, _) = Vfs::new(vec![entry("/foo")], Box::new(|_task| ()), Watch(false));
+        other.add_file_overlay(Path::new("/foo/main.rs"), "lib\r\n".to_string());
+        other.add_file_overlay(Path::new("/foo/lib.rs"), "lib".to_string());
+        assert_eq!(other.root_hash(root), root_hash);
+        other.remove_file_overlay(Path::new("/foo/main.rs"));
+        other.remove_file_overlay(Path::new("/foo/lib.rs"));
+        assert_eq!(other.root_hash(root), empty_root_hash);
+    }
 }
@@ -0,0 +1,238 @@
+//! Limiting the memory used by the texts of files.
+//!
+//! Texts are interned by their contents, so identical files (like vendored
+//! copies of the same crate) share a single allocation. The threads which
+//! read files intern the texts as they read them, so that the copies are
+//! freed before a whole root is sent to the VFS.
+use std::{
+    collections::{hash_map::Entry, BTreeMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::FxHashMap;
+
+use crate::{recording::Event, FileContents, Vfs, VfsFile};
+
+/// Statistics of the memory used by the texts of files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Total size of the loaded texts, in bytes.
+    pub text_bytes: usize,
+    /// Size of the distinct loaded texts, in bytes. This is the memory which
+    /// is actually used, as identical texts are stored once.
+    pub unique_text_bytes: usize,
+    /// Number of files whose texts are loaded.
+    pub loaded_files: usize,
+    pub budget: Option<usize>,
+    /// Number of texts evicted to stay within the budget.
+    pub evictions: u64,
+    /// Number of evicted texts which were read again.
+    pub reloads: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MemoryState {
+    pub(crate) budget: Option<usize>,
+    text_bytes: usize,
+    unique_text_bytes: usize,
+    /// Distinct texts by their hashes, with the number of files using them.
+    interned: FxHashMap<u64, (Arc<String>, usize)>,
+    pub(crate) evictions: u64,
+    pub(crate) reloads: u64,
+    /// Orders the accesses to files, shared with the snapshots.
+    pub(crate) access: Arc<AccessTimes>,
+    /// Files whose texts can be evicted, by the tick of their last access.
+    ///
+    /// Reads with `file_text` don't move the files here, as they only have
+    /// shared access. They are taken into account by the eviction instead.
+    pub(crate) lru: BTreeMap<u64, VfsFile>,
+}
+
+/// A clock which is advanced on every access to a file, and the ticks at
+/// which the texts of the files were last read with `file_text`.
+///
+/// Shared with the snapshots, so that the reads through them count as well.
+#[derive(Debug, Default)]
+pub(crate) struct AccessTimes {
+    clock: AtomicU64,
+    /// Indexed by the ids of the files.
+    last_read: RwLock<Vec<AtomicU64>>,
+}
+
+impl AccessTimes {
+    pub(crate) fn add_file(&self) {
+        self.last_read.write().push(AtomicU64::new(0));
+    }
+
+    pub(crate) fn read(&self, file: VfsFile) {
+        let tick = self.tick();
+        if let Some(last_read) = self.last_read.read().get(file.0 as usize) {
+            last_read.store(tick, Ordering::Relaxed);
+        }
+    }
+
+    fn last_read(&self, file: VfsFile) -> u64 {
+        self.last_read.read().get(file.0 as usize).map_or(0, |it| it.load(Ordering::Relaxed))
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl MemoryState {
+    pub(crate) fn tick(&mut self) -> u64 {
+        self.access.tick()
+    }
+
+    /// Accounts for the `contents` of a file, replacing its text with an
+    /// identical interned one, if any.
+    pub(crate) fn intern(&mut self, contents: &mut FileContents) {
+        self.text_bytes += contents.text.len();
+        match self.interned.entry(contents.hash) {
+            Entry::Occupied(mut entry) => {
+                let (text, users) = entry.get_mut();
+                if *text == contents.text {
+                    contents.text = Arc::clone(text);
+                    *users += 1;
+                } else {
+                    // A hash collision: keep the text as is.
+                    self.unique_text_bytes += contents.text.len();
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((Arc::clone(&contents.text), 1));
+                self.unique_text_bytes += contents.text.len();
+            }
+        }
+    }
+
+    /// Accounts for the `contents` of a file being dropped.
+    pub(crate) fn release(&mut self, contents: &FileContents) {
+        self.text_bytes -= contents.text.len();
+        match self.interned.get_mut(&contents.hash) {
+            Some((text, users)) if Arc::ptr_eq(text, &contents.text) => {
+                *users -= 1;
+                if *users == 0 {
+                    self.interned.remove(&contents.hash);
+                    self.unique_text_bytes -= contents.text.len();
+                }
+            }
+            _ => self.unique_text_bytes -= contents.text.len(),
+        }
+    }
+}
+
+/// Interns the texts read by the IO threads.
+///
+/// Only weak references are kept, so a text is freed once no file uses it.
+#[derive(Default)]
+pub(crate) struct TextInterner {
+    state: Mutex<InternerState>,
+}
+
+#[derive(Default)]
+struct InternerState {
+    texts: FxHashMap<u64, Weak<String>>,
+    /// Freed texts are pruned once there are this many texts.
+    prune_at: usize,
+}
+
+impl TextInterner {
+    /// Replaces the text of `contents` with an identical one read before, if
+    /// it is still used.
+    pub(crate) fn intern(&self, contents: &mut FileContents) {
+        let mut state = self.state.lock();
+        match state.texts.get(&contents.hash).and_then(Weak::upgrade) {
+            Some(text) => {
+                // Keep the text as is on a hash collision.
+                if text == contents.text {
+                    contents.text = text;
+                }
+            }
+            None => {
+                if state.texts.len() >= state.prune_at {
+                    state.texts.retain(|_, text| text.strong_count() > 0);
+                    state.prune_at = (2 * state.texts.len()).max(1024);
+                }
+                state.texts.insert(contents.hash, Arc::downgrade(&contents.text));
+            }
+        }
+    }
+}
+
+impl Vfs {
+    /// Limits the total size of the distinct texts of files to `budget` bytes,
+    /// or removes the limit if `budget` is `None`.
+    ///
+    /// When the budget is exceeded, texts of the least recently accessed files
+    /// are evicted. Reading a text with `file_text`, of the VFS or of any of
+    /// its snapshots, counts as an access. Only texts which can be cheaply
+    /// read again from disk are evicted: overlays, virtual files and files of
+    /// archive, virtual and detached roots are always kept, as reading a file
+    /// of an archive means decompressing all of it.
+    ///
+    /// `file_text` returns `None` for evicted files, while `load_text` and
+    /// `request_text` transparently read them again. Eviction is not reported
+    /// as a change, and neither is reloading unless the text on disk differs
+    /// from the evicted one.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.record(|| Event::SetMemoryBudget(budget));
+        self.memory.budget = budget;
+        self.enforce_memory_budget();
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let loaded_files = self
+            .root2files
+            .values()
+            .flat_map(|files| files.iter())
+            .filter(|&&file| self.file(file).contents.is_some())
+            .count();
+        MemoryStats {
+            text_bytes: self.memory.text_bytes,
+            unique_text_bytes: self.memory.unique_text_bytes,
+            loaded_files,
+            budget: self.memory.budget,
+            evictions: self.memory.evictions,
+            reloads: self.memory.reloads,
+        }
+    }
+
+    pub(crate) fn enforce_memory_budget(&mut self) {
+        let budget = match self.memory.budget {
+            Some(budget) if self.memory.unique_text_bytes > budget => budget,
+            _ => return,
+        };
+        while self.memory.unique_text_bytes > budget {
+            let (tick, file) = match self.memory.lru.iter().next() {
+                Some((&tick, &file)) => (tick, file),
+                None => break,
+            };
+            // The text was read since it was last accessed otherwise, so it
+            // is not the least recently used one.
+            let last_read = self.memory.access.last_read(file);
+            if last_read > tick {
+                self.unindex_file(file);
+                self.file_mut(file).last_accessed = last_read;
+                self.index_file(file);
+                continue;
+            }
+            self.evict(file);
+        }
+    }
+
+    fn evict(&mut self, file: VfsFile) {
+        self.unindex_file(file);
+        let data = self.file_mut(file);
+        let contents = data.contents.take().unwrap();
+        data.evicted = Some((contents.hash, contents.line_endings));
+        self.index_file(file);
+        self.memory.release(&contents);
+        self.memory.evictions += 1;
+    }
+}
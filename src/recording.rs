@@ -0,0 +1,768 @@
+//! Recording the inputs of a VFS to a file, and replaying them without the
+//! file system, to reproduce bugs reported by users.
+//!
+//! The recording contains everything which changes the state of the VFS: the
+//! results of the IO thread, the calls of the API (overlays, virtual files,
+//! new roots) and the files read synchronously on the calling thread. Tasks
+//! sent to the IO thread are recorded as well, though they are not replayed:
+//! their effects are in the recorded results.
+use std::{
+    collections::VecDeque,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+use relative_path::RelativePathBuf;
+
+use crate::{
+    cache::{invalid_data, write_bytes, write_metadata, write_str, write_u64, Reader},
+    hash,
+    io::{Task, TaskResult},
+    roots::{RootKind, Roots},
+    ChangeOrigin, FileContents, FileSystem, Filter, LineEndings, MemoryFileSystem, Metadata,
+    RootEntry, Vfs, VfsFile, VfsProgress, VfsRoot, VfsTask,
+};
+
+/// Identifies the format of the recording. Bump the version whenever the
+/// format changes.
+const MAGIC: &[u8; 8] = b"RAVFSR02";
+
+/// Returns the filter of the root at a path, as filters are not recorded.
+type FilterFn = dyn FnMut(&Path) -> Box<dyn Filter>;
+
+/// A root of the recorded VFS, without its filter.
+#[derive(Debug, Clone)]
+pub(crate) struct RootSpec {
+    path: PathBuf,
+    kind: RootKind,
+    lazy: bool,
+    priority: i32,
+}
+
+impl RootSpec {
+    fn entry(&self, filter: &mut FilterFn) -> RootEntry {
+        RootEntry {
+            path: self.path.clone(),
+            filter: filter(&self.path),
+            kind: self.kind,
+            lazy: self.lazy,
+            priority: self.priority,
+        }
+    }
+}
+
+/// A task sent to the IO thread.
+#[derive(Debug)]
+pub(crate) enum RecordedTask {
+    AddRoot(VfsRoot),
+    /// With the new number of roots.
+    UpdateRoots(usize),
+    NotifyChanged(PathBuf, ChangeOrigin),
+    Prioritize(VfsRoot),
+    CancelLoad(VfsRoot),
+    ValidateArchive(VfsRoot, Metadata),
+    Barrier,
+    ValidateRoot(VfsRoot),
+}
+
+#[derive(Debug)]
+pub(crate) enum Event {
+    Task(RecordedTask),
+    Result(TaskResult),
+    AddOverlay {
+        path: PathBuf,
+        text: String,
+        version: Option<i64>,
+    },
+    /// Recorded with the text after the edit.
+    ChangeOverlay {
+        path: PathBuf,
+        text: String,
+        version: Option<i64>,
+    },
+    RemoveOverlay {
+        path: PathBuf,
+    },
+    AddRoot(RootSpec),
+    AddVirtualRoot(PathBuf),
+    AddVirtualFile {
+        root: VfsRoot,
+        path: RelativePathBuf,
+        text: String,
+    },
+    RemoveVirtualFile(VfsFile),
+    Load(PathBuf),
+    LoadText(VfsFile),
+    SetMemoryBudget(Option<usize>),
+    /// A file read on the calling thread, or `None` if it couldn't be read.
+    ///
+    /// Files are read in the middle of the calls, so the reads of a call are
+    /// recorded after it, but replayed before it.
+    Read {
+        path: PathBuf,
+        contents: Option<Vec<u8>>,
+    },
+}
+
+impl Event {
+    pub(crate) fn task(task: &Task) -> Event {
+        Event::Task(match task {
+            Task::AddRoot { root } => RecordedTask::AddRoot(*root),
+            Task::UpdateRoots { roots } => RecordedTask::UpdateRoots(roots.len()),
+            Task::NotifyChanged { path, origin } => {
+                RecordedTask::NotifyChanged(path.clone(), *origin)
+            }
+            Task::Prioritize { root } => RecordedTask::Prioritize(*root),
+            Task::CancelLoad { root } => RecordedTask::CancelLoad(*root),
+            Task::ValidateArchive { root, metadata } => {
+                RecordedTask::ValidateArchive(*root, *metadata)
+            }
+            #[cfg(feature = "test-support")]
+            Task::Barrier { .. } => RecordedTask::Barrier,
+            Task::ValidateRoot { root, .. } => RecordedTask::ValidateRoot(*root),
+        })
+    }
+
+    pub(crate) fn add_root(entry: &RootEntry) -> Event {
+        Event::AddRoot(RootSpec {
+            path: entry.path.clone(),
+            kind: entry.kind,
+            lazy: entry.lazy,
+            priority: entry.priority,
+        })
+    }
+}
+
+pub(crate) struct Recorder {
+    file: fs::File,
+    /// Set once writing failed, which stops the recording.
+    failed: bool,
+    /// Reads held back by `Vfs::hold_reads`, until the next other event.
+    held: Option<Vec<u8>>,
+}
+
+impl Recorder {
+    fn write(&mut self, event: &Event) {
+        if self.failed {
+            return;
+        }
+        let mut buf = Vec::new();
+        write_time(&mut buf, SystemTime::now());
+        write_event(&mut buf, event);
+        match &mut self.held {
+            Some(held) if matches!(event, Event::Read { .. }) => {
+                held.extend_from_slice(&buf);
+                return;
+            }
+            _ => buf.extend(self.held.take().unwrap_or_default()),
+        }
+        if let Err(e) = self.file.write_all(&buf) {
+            log::warn!("failed to record VFS event: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+/// Records the files read on the calling thread.
+struct RecordingFileSystem {
+    inner: Arc<dyn FileSystem>,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl FileSystem for RecordingFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let res = self.inner.read(path);
+        let contents = res.as_ref().ok().cloned();
+        self.recorder.lock().write(&Event::Read { path: path.to_path_buf(), contents });
+        res
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)> {
+        self.inner.walk(dir, include)
+    }
+}
+
+impl Vfs {
+    /// Starts recording the inputs of the VFS to the file at `path`, to be
+    /// replayed later with `Replay`.
+    ///
+    /// The recording lasts as long as the `Vfs`. It must start before the
+    /// first change of the VFS, that is right after it is created: roots which
+    /// were served from a cache or loaded with `load_sync` can't be recorded.
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        if self.revision != 0 || self.recorder.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "recording must start before the first change of the VFS",
+            ));
+        }
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        write_u64(&mut buf, self.roots.len() as u64);
+        for root in self.roots.iter() {
+            write_root(
+                &mut buf,
+                &RootSpec {
+                    path: self.roots.path(root).to_path_buf(),
+                    kind: self.roots.kind(root),
+                    lazy: self.roots.is_lazy(root),
+                    priority: self.roots.priority(root),
+                },
+            );
+        }
+        let mut file = fs::File::create(path)?;
+        file.write_all(&buf)?;
+
+        let recorder = Arc::new(Mutex::new(Recorder { file, failed: false, held: None }));
+        let inner = Arc::clone(&self.fs);
+        self.fs = Arc::new(RecordingFileSystem { inner, recorder: Arc::clone(&recorder) });
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Records the event built by `event`, if recording.
+    pub(crate) fn record(&self, event: impl FnOnce() -> Event) {
+        if let Some(recorder) = &self.recorder {
+            recorder.lock().write(&event());
+        }
+    }
+
+    /// Holds back the files read on the calling thread until the next event
+    /// is recorded, for the calls which only know their event after reading.
+    pub(crate) fn hold_reads(&self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.lock().held.get_or_insert_with(Vec::new);
+        }
+    }
+}
+
+/// Inputs of a VFS, recorded with `Vfs::start_recording`.
+pub struct Recording {
+    roots: Vec<RootSpec>,
+    events: Vec<(SystemTime, Event)>,
+}
+
+impl fmt::Debug for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Recording")
+            .field("roots", &self.roots)
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl Recording {
+    /// Reads a recording. An incomplete last event, as left if the recorded
+    /// process crashed, is skipped.
+    pub fn read(path: &Path) -> io::Result<Recording> {
+        let bytes = fs::read(path)?;
+        let mut reader = Reader::new(&bytes, "VFS recording");
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("not a VFS recording, or an unsupported version"));
+        }
+        let mut roots = Vec::new();
+        for _ in 0..reader.u64()? {
+            roots.push(read_root(&mut reader)?);
+        }
+        let mut events = Vec::new();
+        while !reader.is_empty() {
+            let event = reader.time().and_then(|time| Ok((time, read_event(&mut reader)?)));
+            match event {
+                Ok(it) => events.push(it),
+                // an incomplete last event, as left by a crash
+                Err(_) if reader.is_truncated() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Recording { roots, events })
+    }
+
+    /// Number of recorded events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// Replays a `Recording` on a `Vfs` backed by a `MemoryFileSystem`, which
+/// holds the files as they were read by the recorded VFS.
+///
+/// The VFS goes through the same states as the recorded one, with the same
+/// `VfsFile`s and `VfsRoot`s, so the changes can be inspected after each step.
+/// The replayed VFS has no IO thread: it only sees the recorded results.
+pub struct Replay {
+    vfs: Vfs,
+    fs: Arc<MemoryFileSystem>,
+    filter: Box<FilterFn>,
+    events: VecDeque<(SystemTime, Event)>,
+}
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("vfs", &self.vfs)
+            .field("n_events", &self.events.len())
+            .finish()
+    }
+}
+
+impl Replay {
+    /// Creates the VFS with the recorded roots. Filters are not recorded:
+    /// `filter` returns the filter of the root at a path.
+    pub fn new(
+        recording: Recording,
+        mut filter: impl FnMut(&Path) -> Box<dyn Filter> + 'static,
+    ) -> Replay {
+        let fs = Arc::new(MemoryFileSystem::new());
+        let entries = recording.roots.iter().map(|it| it.entry(&mut filter)).collect();
+        let roots = Arc::new(Roots::new(entries, &*fs));
+        let mut vfs = Vfs::with_worker(roots, Arc::clone(&fs) as Arc<dyn FileSystem>, None);
+        for root in vfs.roots.iter().collect::<Vec<_>>() {
+            vfs.loading_roots.insert(root, Default::default());
+        }
+        Replay { vfs, fs, filter: Box::new(filter), events: recording.events.into() }
+    }
+
+    pub fn vfs(&mut self) -> &mut Vfs {
+        &mut self.vfs
+    }
+
+    /// Replays the next event, returning the time it was recorded at, or
+    /// `None` if all events were replayed.
+    pub fn step(&mut self) -> Option<SystemTime> {
+        loop {
+            let (time, event) = self.events.pop_front()?;
+            if let Event::Read { path, contents } = event {
+                self.apply_read(path, contents);
+                continue;
+            }
+            while let Some((_, Event::Read { .. })) = self.events.front() {
+                if let Some((_, Event::Read { path, contents })) = self.events.pop_front() {
+                    self.apply_read(path, contents);
+                }
+            }
+            self.apply(event);
+            return Some(time);
+        }
+    }
+
+    /// Replays the remaining events and returns the VFS.
+    pub fn finish(mut self) -> Vfs {
+        while self.step().is_some() {}
+        self.vfs
+    }
+
+    fn apply_read(&mut self, path: PathBuf, contents: Option<Vec<u8>>) {
+        match contents {
+            Some(contents) => self.fs.write(path, contents),
+            None => {
+                self.fs.remove(&path);
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        let vfs = &mut self.vfs;
+        match event {
+            Event::Task(_) | Event::Read { .. } => (),
+            Event::Result(res) => vfs.handle_task(VfsTask(res)),
+            Event::AddOverlay { path, text, version: Some(version) } => {
+                vfs.add_file_overlay_with_version(&path, text, version);
+            }
+            Event::AddOverlay { path, text, version: None } => {
+                vfs.add_file_overlay(&path, text);
+            }
+            Event::ChangeOverlay { path, text, version: Some(version) } => {
+                vfs.change_file_overlay_with_version(&path, version, |it| *it = text);
+            }
+            Event::ChangeOverlay { path, text, version: None } => {
+                vfs.change_file_overlay(&path, |it| *it = text);
+            }
+            Event::RemoveOverlay { path } => {
+                vfs.remove_file_overlay(&path);
+            }
+            Event::AddRoot(spec) => {
+                // the files of the root are in the recorded results
+                vfs.add_root_without_loading(spec.entry(&mut self.filter));
+            }
+            Event::AddVirtualRoot(path) => {
+                vfs.add_virtual_root(path);
+            }
+            Event::AddVirtualFile { root, path, text } => {
+                vfs.add_virtual_file(root, path, text);
+            }
+            Event::RemoveVirtualFile(file) => vfs.remove_virtual_file(file),
+            Event::Load(path) => {
+                vfs.load(&path);
+            }
+            Event::LoadText(file) => {
+                vfs.load_text(file);
+            }
+            Event::SetMemoryBudget(budget) => vfs.set_memory_budget(budget),
+        }
+    }
+}
+
+fn write_time(buf: &mut Vec<u8>, time: SystemTime) {
+    let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    write_u64(buf, time.as_secs());
+    write_u64(buf, u64::from(time.subsec_nanos()));
+}
+
+fn write_path(buf: &mut Vec<u8>, path: &Path) {
+    write_str(buf, &path.to_string_lossy());
+}
+
+fn read_path(reader: &mut Reader) -> io::Result<PathBuf> {
+    Ok(PathBuf::from(reader.str()?))
+}
+
+fn write_root(buf: &mut Vec<u8>, root: &RootSpec) {
+    write_path(buf, &root.path);
+    buf.push(match root.kind {
+        RootKind::Disk => 0,
+        RootKind::Archive => 1,
+        RootKind::Virtual => 2,
+        RootKind::Detached => 3,
+    });
+    buf.push(root.lazy as u8);
+    write_u64(buf, i64::from(root.priority) as u64);
+}
+
+fn read_root(reader: &mut Reader) -> io::Result<RootSpec> {
+    let path = read_path(reader)?;
+    let kind = match reader.u8()? {
+        0 => RootKind::Disk,
+        1 => RootKind::Archive,
+        2 => RootKind::Virtual,
+        3 => RootKind::Detached,
+        _ => return Err(invalid_data("invalid root kind")),
+    };
+    let lazy = reader.u8()? != 0;
+    let priority = reader.u64()? as i64 as i32;
+    Ok(RootSpec { path, kind, lazy, priority })
+}
+
+fn write_origin(buf: &mut Vec<u8>, origin: ChangeOrigin) {
+    buf.push(match origin {
+        ChangeOrigin::Overlay => 0,
+        ChangeOrigin::Watcher => 1,
+        ChangeOrigin::Notification => 2,
+        ChangeOrigin::Reconcile => 3,
+        ChangeOrigin::Api => 4,
+    });
+}
+
+fn read_origin(reader: &mut Reader) -> io::Result<ChangeOrigin> {
+    Ok(match reader.u8()? {
+        0 => ChangeOrigin::Overlay,
+        1 => ChangeOrigin::Watcher,
+        2 => ChangeOrigin::Notification,
+        3 => ChangeOrigin::Reconcile,
+        4 => ChangeOrigin::Api,
+        _ => return Err(invalid_data("invalid change origin")),
+    })
+}
+
+fn write_opt_u64(buf: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            write_u64(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_opt_u64(reader: &mut Reader) -> io::Result<Option<u64>> {
+    Ok(if reader.u8()? != 0 { Some(reader.u64()?) } else { None })
+}
+
+fn write_contents(buf: &mut Vec<u8>, contents: &FileContents) {
+    buf.push(match contents.line_endings {
+        LineEndings::Unix => 0,
+        LineEndings::Dos => 1,
+    });
+    write_str(buf, &contents.text);
+}
+
+fn read_contents(reader: &mut Reader) -> io::Result<FileContents> {
+    let line_endings = if reader.u8()? != 0 { LineEndings::Dos } else { LineEndings::Unix };
+    let text = reader.str()?;
+    let hash = hash::hash_text(&text);
+    Ok(FileContents { text: Arc::new(text), line_endings, hash })
+}
+
+fn write_opt_contents(buf: &mut Vec<u8>, contents: &Option<FileContents>) {
+    match contents {
+        Some(contents) => {
+            buf.push(1);
+            write_contents(buf, contents);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_opt_contents(reader: &mut Reader) -> io::Result<Option<FileContents>> {
+    Ok(if reader.u8()? != 0 { Some(read_contents(reader)?) } else { None })
+}
+
+fn write_event(buf: &mut Vec<u8>, event: &Event) {
+    match event {
+        Event::Task(task) => {
+            buf.push(0);
+            write_task(buf, task);
+        }
+        Event::Result(res) => {
+            buf.push(1);
+            write_result(buf, res);
+        }
+        Event::AddOverlay { path, text, version } => {
+            buf.push(2);
+            write_path(buf, path);
+            write_str(buf, text);
+            write_opt_u64(buf, version.map(|it| it as u64));
+        }
+        Event::ChangeOverlay { path, text, version } => {
+            buf.push(3);
+            write_path(buf, path);
+            write_str(buf, text);
+            write_opt_u64(buf, version.map(|it| it as u64));
+        }
+        Event::RemoveOverlay { path } => {
+            buf.push(4);
+            write_path(buf, path);
+        }
+        Event::AddRoot(root) => {
+            buf.push(5);
+            write_root(buf, root);
+        }
+        Event::AddVirtualRoot(path) => {
+            buf.push(6);
+            write_path(buf, path);
+        }
+        Event::AddVirtualFile { root, path, text } => {
+            buf.push(7);
+            write_u64(buf, u64::from(root.0));
+            write_str(buf, path.as_str());
+            write_str(buf, text);
+        }
+        Event::RemoveVirtualFile(file) => {
+            buf.push(8);
+            write_u64(buf, u64::from(file.0));
+        }
+        Event::Load(path) => {
+            buf.push(9);
+            write_path(buf, path);
+        }
+        Event::LoadText(file) => {
+            buf.push(10);
+            write_u64(buf, u64::from(file.0));
+        }
+        Event::SetMemoryBudget(budget) => {
+            buf.push(11);
+            write_opt_u64(buf, budget.map(|it| it as u64));
+        }
+        Event::Read { path, contents } => {
+            buf.push(12);
+            write_path(buf, path);
+            match contents {
+                Some(contents) => {
+                    buf.push(1);
+                    write_bytes(buf, contents);
+                }
+                None => buf.push(0),
+            }
+        }
+    }
+}
+
+fn read_event(reader: &mut Reader) -> io::Result<Event> {
+    let event = match reader.u8()? {
+        0 => Event::Task(read_task(reader)?),
+        1 => Event::Result(read_result(reader)?),
+        2 => Event::AddOverlay {
+            path: read_path(reader)?,
+            text: reader.str()?,
+            version: read_opt_u64(reader)?.map(|it| it as i64),
+        },
+        3 => Event::ChangeOverlay {
+            path: read_path(reader)?,
+            text: reader.str()?,
+            version: read_opt_u64(reader)?.map(|it| it as i64),
+        },
+        4 => Event::RemoveOverlay { path: read_path(reader)? },
+        5 => Event::AddRoot(read_root(reader)?),
+        6 => Event::AddVirtualRoot(read_path(reader)?),
+        7 => Event::AddVirtualFile {
+            root: read_root_id(reader)?,
+            path: RelativePathBuf::from(reader.str()?),
+            text: reader.str()?,
+        },
+        8 => Event::RemoveVirtualFile(VfsFile(reader.u64()? as u32)),
+        9 => Event::Load(read_path(reader)?),
+        10 => Event::LoadText(VfsFile(reader.u64()? as u32)),
+        11 => Event::SetMemoryBudget(read_opt_u64(reader)?.map(|it| it as usize)),
+        12 => Event::Read {
+            path: read_path(reader)?,
+            contents: if reader.u8()? != 0 { Some(reader.byte_string()?.to_vec()) } else { None },
+        },
+        _ => return Err(invalid_data("invalid event")),
+    };
+    Ok(event)
+}
+
+fn read_root_id(reader: &mut Reader) -> io::Result<VfsRoot> {
+    Ok(VfsRoot(reader.u64()? as u32))
+}
+
+fn write_task(buf: &mut Vec<u8>, task: &RecordedTask) {
+    match task {
+        RecordedTask::AddRoot(root) => {
+            buf.push(0);
+            write_u64(buf, u64::from(root.0));
+        }
+        RecordedTask::UpdateRoots(n_roots) => {
+            buf.push(1);
+            write_u64(buf, *n_roots as u64);
+        }
+        RecordedTask::NotifyChanged(path, origin) => {
+            buf.push(2);
+            write_path(buf, path);
+            write_origin(buf, *origin);
+        }
+        RecordedTask::Prioritize(root) => {
+            buf.push(3);
+            write_u64(buf, u64::from(root.0));
+        }
+        RecordedTask::CancelLoad(root) => {
+            buf.push(4);
+            write_u64(buf, u64::from(root.0));
+        }
+        RecordedTask::ValidateArchive(root, metadata) => {
+            buf.push(5);
+            write_u64(buf, u64::from(root.0));
+            write_metadata(buf, *metadata);
+        }
+        RecordedTask::Barrier => buf.push(6),
+        RecordedTask::ValidateRoot(root) => {
+            buf.push(7);
+            write_u64(buf, u64::from(root.0));
+        }
+    }
+}
+
+fn read_task(reader: &mut Reader) -> io::Result<RecordedTask> {
+    let task = match reader.u8()? {
+        0 => RecordedTask::AddRoot(read_root_id(reader)?),
+        1 => RecordedTask::UpdateRoots(reader.u64()? as usize),
+        2 => RecordedTask::NotifyChanged(read_path(reader)?, read_origin(reader)?),
+        3 => RecordedTask::Prioritize(read_root_id(reader)?),
+        4 => RecordedTask::CancelLoad(read_root_id(reader)?),
+        5 => RecordedTask::ValidateArchive(read_root_id(reader)?, reader.metadata()?),
+        6 => RecordedTask::Barrier,
+        7 => RecordedTask::ValidateRoot(read_root_id(reader)?),
+        _ => return Err(invalid_data("invalid task")),
+    };
+    Ok(task)
+}
+
+fn write_result(buf: &mut Vec<u8>, res: &TaskResult) {
+    match res {
+        TaskResult::LoadRootChunk { root, files } | TaskResult::BulkLoadRoot { root, files } => {
+            buf.push(if let TaskResult::LoadRootChunk { .. } = res { 0 } else { 1 });
+            write_u64(buf, u64::from(root.0));
+            write_u64(buf, files.len() as u64);
+            for (path, contents) in files {
+                write_str(buf, path.as_str());
+                write_opt_contents(buf, contents);
+            }
+        }
+        TaskResult::SingleFile { root, path, contents, origin, time } => {
+            buf.push(2);
+            write_u64(buf, u64::from(root.0));
+            write_str(buf, path.as_str());
+            write_opt_contents(buf, contents);
+            write_origin(buf, *origin);
+            write_time(buf, *time);
+        }
+        TaskResult::ReloadRoot { root, files, origin, time } => {
+            buf.push(3);
+            write_u64(buf, u64::from(root.0));
+            write_u64(buf, files.len() as u64);
+            for (path, contents) in files {
+                write_str(buf, path.as_str());
+                write_contents(buf, contents);
+            }
+            write_origin(buf, *origin);
+            write_time(buf, *time);
+        }
+        TaskResult::Progress(progress) => {
+            buf.push(4);
+            write_u64(buf, u64::from(progress.root.0));
+            write_u64(buf, progress.n_files_discovered as u64);
+            write_u64(buf, progress.n_files_read as u64);
+        }
+    }
+}
+
+fn read_result(reader: &mut Reader) -> io::Result<TaskResult> {
+    let res = match reader.u8()? {
+        tag @ (0 | 1) => {
+            let root = read_root_id(reader)?;
+            let mut files = Vec::new();
+            for _ in 0..reader.u64()? {
+                let path = RelativePathBuf::from(reader.str()?);
+                files.push((path, read_opt_contents(reader)?));
+            }
+            if tag == 0 {
+                TaskResult::LoadRootChunk { root, files }
+            } else {
+                TaskResult::BulkLoadRoot { root, files }
+            }
+        }
+        2 => TaskResult::SingleFile {
+            root: read_root_id(reader)?,
+            path: RelativePathBuf::from(reader.str()?),
+            contents: read_opt_contents(reader)?,
+            origin: read_origin(reader)?,
+            time: reader.time()?,
+        },
+        3 => {
+            let root = read_root_id(reader)?;
+            let mut files = Vec::new();
+            for _ in 0..reader.u64()? {
+                let path = RelativePathBuf::from(reader.str()?);
+                files.push((path, read_contents(reader)?));
+            }
+            let origin = read_origin(reader)?;
+            TaskResult::ReloadRoot { root, files, origin, time: reader.time()? }
+        }
+        4 => TaskResult::Progress(VfsProgress {
+            root: read_root_id(reader)?,
+            n_files_discovered: reader.u64()? as usize,
+            n_files_read: reader.u64()? as usize,
+        }),
+        _ => return Err(invalid_data("invalid task result")),
+    };
+    Ok(res)
+}
@@ -1,11 +1,12 @@
 use std::{
     iter,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use relative_path::RelativePathBuf;
+use relative_path::{RelativePath, RelativePathBuf};
 
-use super::{RootEntry, Filter};
+use super::{RootEntry, Filter, FileSystem, Metadata};
 
 /// VfsRoot identifies a watched directory on the file system.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -23,12 +24,27 @@ impl FileType {
     }
 }
 
-impl std::convert::From<std::fs::FileType> for FileType {
-    fn from(v: std::fs::FileType) -> Self {
-        if v.is_file() {
-            FileType::File
-        } else {
+/// Where the files of a root come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RootKind {
+    /// A directory on disk, which is loaded and (optionally) watched.
+    Disk,
+    /// A `.crate` or `.tar.gz` archive on disk. Its files are immutable, but
+    /// the whole root is reloaded if the archive itself changes.
+    Archive,
+    /// A root whose files are all added via `Vfs::add_virtual_file` or
+    /// overlays. It is never read from disk.
+    Virtual,
+    /// A root for a single overlay outside of any other root.
+    Detached,
+}
+
+impl std::convert::From<&Metadata> for FileType {
+    fn from(v: &Metadata) -> Self {
+        if v.is_dir {
             FileType::Dir
+        } else {
+            FileType::File
         }
     }
 }
@@ -38,20 +54,56 @@ impl std::convert::From<std::fs::FileType> for FileType {
 /// `RootData` can be thought of as a glob pattern like `src/**.rs` which
 /// specifies the source root or as a function which takes a `PathBuf` and
 /// returns `true` if path belongs to the source root
+#[derive(Clone)]
 struct RootData {
     root: PathBuf,
-    filter: Box<dyn Filter>,
+    filter: Arc<dyn Filter>,
     // result of `root.canonicalize()` if that differs from `root`; `None` otherwise.
     canonical_path: Option<PathBuf>,
     excluded_dirs: Vec<RelativePathBuf>,
+    kind: RootKind,
+    // if `true`, texts of the files are not read by the initial scan.
+    lazy: bool,
+    // roots with higher priorities are loaded first.
+    priority: i32,
+    // set once a detached root is removed. Its id is never reused.
+    removed: bool,
+}
+
+/// Filter of a virtual root, which includes everything.
+struct IncludeAll;
+
+impl Filter for IncludeAll {
+    fn include_dir(&self, _dir_path: &RelativePath) -> bool {
+        true
+    }
+    fn include_file(&self, _file_path: &RelativePath) -> bool {
+        true
+    }
+}
+
+/// Filter of a detached root, which includes a single file only.
+struct DetachedFilter(RelativePathBuf);
+
+impl Filter for DetachedFilter {
+    fn include_dir(&self, dir_path: &RelativePath) -> bool {
+        dir_path.as_str().is_empty()
+    }
+    fn include_file(&self, file_path: &RelativePath) -> bool {
+        file_path == self.0
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct Roots {
     roots: Vec<RootData>,
+    // Roots in the order they should be tried by `find`: nested roots come
+    // before their parents.
+    order: Vec<VfsRoot>,
 }
 
 impl Roots {
-    pub(crate) fn new(mut paths: Vec<RootEntry>) -> Roots {
+    pub(crate) fn new(mut paths: Vec<RootEntry>, fs: &dyn FileSystem) -> Roots {
         paths.sort_by(|a, b| a.path.cmp(&b.path));
         paths.dedup();
 
@@ -73,27 +125,134 @@ impl Roots {
         // Then combine the entry with the matching nested_roots
         let roots = paths
             .into_iter()
-            .zip(nested_roots.into_iter())
-            .map(|(entry, nested_roots)| RootData::new(entry, nested_roots))
+            .zip(nested_roots)
+            .map(|(entry, nested_roots)| RootData::new(entry, nested_roots, fs))
             .collect::<Vec<_>>();
 
-        Roots { roots }
+        let order = (0..roots.len()).map(|idx| VfsRoot(idx as u32)).collect();
+        Roots { roots, order }
+    }
+
+    /// Adds a new root, excluding it from the roots it is nested in.
+    ///
+    /// Returns the existing root if there is already one with the same path.
+    pub(crate) fn add(&mut self, entry: RootEntry, fs: &dyn FileSystem) -> VfsRoot {
+        if let Some(root) = self.iter().find(|&root| {
+            let data = self.root(root);
+            data.kind != RootKind::Detached && data.path() == entry.path
+        }) {
+            return root;
+        }
+
+        let mut nested_roots = Vec::new();
+        for data in self.roots.iter_mut().filter(|it| it.kind != RootKind::Detached) {
+            if let Some(rel_path) = rel_path(&entry.path, &data.root) {
+                nested_roots.push(rel_path);
+            } else if let Some(rel_path) = rel_path(&data.root, &entry.path) {
+                data.excluded_dirs.push(rel_path);
+            }
+        }
+        self.push(RootData::new(entry, nested_roots, fs))
+    }
+
+    pub(crate) fn add_virtual(&mut self, path: PathBuf, fs: &dyn FileSystem) -> VfsRoot {
+        let entry = RootEntry {
+            path,
+            filter: Box::new(IncludeAll),
+            kind: RootKind::Virtual,
+            lazy: false,
+            priority: 0,
+        };
+        self.add(entry, fs)
+    }
+
+    /// Adds a root which contains only the file at `path`. Detached roots are
+    /// never watched or read from disk, and are not considered by `find`.
+    pub(crate) fn add_detached(&mut self, path: &Path) -> Option<(VfsRoot, RelativePathBuf)> {
+        let (dir, file_name) = (path.parent()?, path.file_name()?);
+        let rel_path = RelativePathBuf::from_path(file_name).ok()?;
+        let data = RootData {
+            root: dir.to_path_buf(),
+            filter: Arc::new(DetachedFilter(rel_path.clone())),
+            canonical_path: None,
+            excluded_dirs: Vec::new(),
+            kind: RootKind::Detached,
+            lazy: false,
+            priority: 0,
+            removed: false,
+        };
+        Some((self.push(data), rel_path))
+    }
+
+    /// Removes a detached root. Its id is retired rather than reused, as
+    /// clients may still have state keyed by it.
+    pub(crate) fn remove_detached(&mut self, root: VfsRoot) {
+        assert_eq!(self.kind(root), RootKind::Detached);
+        self.order.retain(|&it| it != root);
+        self.roots[root.0 as usize].removed = true;
     }
+
+    fn push(&mut self, data: RootData) -> VfsRoot {
+        let len = data.root.as_os_str().len();
+        self.roots.push(data);
+        let root = VfsRoot(self.roots.len() as u32 - 1);
+        // Keep `order` sorted, with the new root after the roots of the same
+        // length.
+        let roots = &self.roots;
+        let idx = self
+            .order
+            .iter()
+            .position(|it| roots[it.0 as usize].root.as_os_str().len() < len)
+            .unwrap_or(self.order.len());
+        self.order.insert(idx, root);
+        root
+    }
+
     pub(crate) fn find(
         &self,
         path: &Path,
         expected: FileType,
     ) -> Option<(VfsRoot, RelativePathBuf)> {
-        self.iter().find_map(|root| {
+        self.order.iter().filter(|&&it| self.kind(it) != RootKind::Detached).find_map(|&root| {
             let rel_path = self.contains(root, path, expected)?;
             Some((root, rel_path))
         })
     }
+
+    /// Like `find`, but looks for a detached root containing the file.
+    pub(crate) fn find_detached(&self, path: &Path) -> Option<(VfsRoot, RelativePathBuf)> {
+        self.iter().filter(|&it| self.kind(it) == RootKind::Detached).find_map(|root| {
+            let rel_path = self.contains(root, path, FileType::File)?;
+            Some((root, rel_path))
+        })
+    }
+
+    /// Checks if `path` lies under the directory of some non-detached root,
+    /// regardless of whether the root's filter includes it.
+    pub(crate) fn covers(&self, path: &Path) -> bool {
+        self.roots.iter().filter(|it| it.kind != RootKind::Detached).any(|data| {
+            iter::once(data.path())
+                .chain(data.canonical_path.iter().map(PathBuf::as_path))
+                .any(|base| path.starts_with(base))
+        })
+    }
+
+    pub(crate) fn kind(&self, root: VfsRoot) -> RootKind {
+        self.root(root).kind
+    }
+    pub(crate) fn is_lazy(&self, root: VfsRoot) -> bool {
+        self.root(root).lazy
+    }
+    pub(crate) fn priority(&self, root: VfsRoot) -> i32 {
+        self.root(root).priority
+    }
     pub(crate) fn len(&self) -> usize {
-        self.roots.len()
+        self.roots.iter().filter(|it| !it.removed).count()
     }
     pub(crate) fn iter<'a>(&'a self) -> impl Iterator<Item = VfsRoot> + 'a {
-        (0..self.roots.len()).into_iter().map(|idx| VfsRoot(idx as u32))
+        (0..self.roots.len())
+            .filter(move |&idx| !self.roots[idx].removed)
+            .map(|idx| VfsRoot(idx as u32))
     }
     pub(crate) fn path(&self, root: VfsRoot) -> &Path {
         self.root(root).path()
@@ -110,7 +269,7 @@ impl Roots {
         let data = self.root(root);
         iter::once(data.path())
             .chain(data.canonical_path.iter().map(PathBuf::as_path))
-            .find_map(|base| to_relative_path(base, path, &data, expected))
+            .find_map(|base| to_relative_path(base, path, data, expected))
     }
 
     fn root(&self, root: VfsRoot) -> &RootData {
@@ -119,12 +278,24 @@ impl Roots {
 }
 
 impl RootData {
-    fn new(entry: RootEntry, excluded_dirs: Vec<RelativePathBuf>) -> RootData {
-        let mut canonical_path = entry.path.canonicalize().ok();
+    fn new(entry: RootEntry, excluded_dirs: Vec<RelativePathBuf>, fs: &dyn FileSystem) -> RootData {
+        let mut canonical_path = match entry.kind {
+            RootKind::Disk | RootKind::Archive => fs.canonicalize(&entry.path).ok(),
+            RootKind::Virtual | RootKind::Detached => None,
+        };
         if Some(&entry.path) == canonical_path.as_ref() {
             canonical_path = None;
         }
-        RootData { root: entry.path, filter: entry.filter, canonical_path, excluded_dirs }
+        RootData {
+            root: entry.path,
+            filter: Arc::from(entry.filter),
+            canonical_path,
+            excluded_dirs,
+            kind: entry.kind,
+            lazy: entry.lazy,
+            priority: entry.priority,
+            removed: false,
+        }
     }
 
     fn path(&self) -> &Path {
@@ -137,16 +308,15 @@ impl RootData {
             return false;
         }
 
-        let parent_included =
-            rel_path.parent().map(|d| self.filter.include_dir(&d)).unwrap_or(true);
+        let parent_included = rel_path.parent().map(|d| self.filter.include_dir(d)).unwrap_or(true);
 
         if !parent_included {
             return false;
         }
 
         match expected {
-            FileType::File => self.filter.include_file(&rel_path),
-            FileType::Dir => self.filter.include_dir(&rel_path),
+            FileType::File => self.filter.include_file(rel_path),
+            FileType::Dir => self.filter.include_dir(rel_path),
         }
     }
 }
@@ -0,0 +1,144 @@
+//! Read-only snapshots of the VFS, which can be shared with other threads.
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    hash::RootHash,
+    memory::AccessTimes,
+    roots::{FileType, Roots},
+    LineEndings, RelativePath, RelativePathBuf, VfsFile, VfsFileData, VfsRoot,
+};
+
+const CHUNK_SIZE: usize = 256;
+
+/// A growable vector which is cheap to clone.
+///
+/// Elements are stored in fixed-size chunks shared between clones, so that
+/// modifying a clone copies only the affected chunk.
+#[derive(Clone)]
+pub(crate) struct SharedVec<T> {
+    chunks: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T: Clone> SharedVec<T> {
+    pub(crate) fn new() -> SharedVec<T> {
+        SharedVec { chunks: Vec::new(), len: 0 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, value: T) {
+        if self.len % CHUNK_SIZE == 0 {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_SIZE)));
+        }
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(value);
+        self.len += 1;
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> &T {
+        &self.chunks[idx / CHUNK_SIZE][idx % CHUNK_SIZE]
+    }
+
+    pub(crate) fn get_mut(&mut self, idx: usize) -> &mut T {
+        &mut Arc::make_mut(&mut self.chunks[idx / CHUNK_SIZE])[idx % CHUNK_SIZE]
+    }
+}
+
+/// An immutable view of the VFS at some point in time.
+///
+/// Snapshots are cheap to create and to clone, as they share the data with
+/// the `Vfs` and with each other. They can be sent to other threads, which
+/// then see a consistent state of all roots and files, regardless of the
+/// changes applied to the `Vfs` later.
+#[derive(Clone)]
+pub struct VfsSnapshot {
+    pub(crate) roots: Arc<Roots>,
+    pub(crate) files: SharedVec<VfsFileData>,
+    pub(crate) root2files: FxHashMap<VfsRoot, Arc<FxHashSet<VfsFile>>>,
+    pub(crate) root_hashes: FxHashMap<VfsRoot, RootHash>,
+    pub(crate) access: Arc<AccessTimes>,
+    pub(crate) revision: u64,
+}
+
+impl fmt::Debug for VfsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VfsSnapshot")
+            .field("revision", &self.revision)
+            .field("n_roots", &self.roots.len())
+            .field("n_files", &self.files.len())
+            .finish()
+    }
+}
+
+impl VfsSnapshot {
+    /// Revision of the `Vfs` this snapshot was taken at.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn n_roots(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn root2path(&self, root: VfsRoot) -> PathBuf {
+        self.roots.path(root).to_path_buf()
+    }
+
+    pub fn path2root(&self, path: &Path) -> Option<VfsRoot> {
+        let (root, _path) = self.roots.find(path, FileType::Dir)?;
+        Some(root)
+    }
+
+    pub fn path2file(&self, path: &Path) -> Option<VfsFile> {
+        let (root, path) =
+            self.roots.find(path, FileType::File).or_else(|| self.roots.find_detached(path))?;
+        self.find_file(root, &path)
+    }
+
+    pub fn file2path(&self, file: VfsFile) -> PathBuf {
+        let data = self.file(file);
+        data.path.to_path(self.roots.path(data.root))
+    }
+
+    pub fn file2root(&self, file: VfsFile) -> VfsRoot {
+        self.file(file).root
+    }
+
+    pub fn file_text(&self, file: VfsFile) -> Option<Arc<String>> {
+        self.access.read(file);
+        self.file(file).text()
+    }
+
+    pub fn file_line_endings(&self, file: VfsFile) -> LineEndings {
+        self.file(file).line_endings()
+    }
+
+    pub fn file_hash(&self, file: VfsFile) -> Option<u64> {
+        self.file(file).hash()
+    }
+
+    pub fn root_hash(&self, root: VfsRoot) -> Option<u64> {
+        self.root_hashes.get(&root).copied().unwrap_or_default().finish()
+    }
+
+    /// Returns all files of the `root`, in no particular order.
+    pub fn root_files(&self, root: VfsRoot) -> Vec<(VfsFile, RelativePathBuf)> {
+        self.root2files[&root].iter().map(|&file| (file, self.file(file).path.clone())).collect()
+    }
+
+    fn find_file(&self, root: VfsRoot, path: &RelativePath) -> Option<VfsFile> {
+        self.root2files[&root].iter().copied().find(|&file| self.file(file).path == path)
+    }
+
+    fn file(&self, file: VfsFile) -> &VfsFileData {
+        self.files.get(file.0 as usize)
+    }
+}
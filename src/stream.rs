@@ -0,0 +1,112 @@
+//! Integration with async runtimes, enabled by the `futures` feature.
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+use parking_lot::Mutex;
+
+use crate::{FileSystem, RootEntry, Vfs, VfsRoot, VfsTask, Watch};
+
+/// `VfsTask`s of a `Vfs` created with `Vfs::with_stream`.
+///
+/// The stream ends when the `Vfs` is dropped.
+#[derive(Debug)]
+pub struct VfsTaskStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    tasks: VecDeque<VfsTask>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// Owned by the `on_task` callback, so that the stream ends once the `vfs`
+/// thread is gone.
+struct Sender {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Sender {
+    fn send(&self, task: VfsTask) {
+        let mut shared = self.shared.lock();
+        shared.tasks.push_back(task);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.closed = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+impl Stream for VfsTaskStream {
+    type Item = VfsTask;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<VfsTask>> {
+        let mut shared = self.shared.lock();
+        if let Some(task) = shared.tasks.pop_front() {
+            return Poll::Ready(Some(task));
+        }
+        if shared.closed {
+            return Poll::Ready(None);
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Vfs {
+    /// Like `with_file_system`, but returns the tasks as a `Stream` instead of
+    /// passing them to a callback.
+    pub fn with_stream(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        watch: Watch,
+    ) -> (Vfs, Vec<VfsRoot>, VfsTaskStream) {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let sender = Sender { shared: Arc::clone(&shared) };
+        let (vfs, roots) =
+            Vfs::with_file_system(roots, fs, Box::new(move |task| sender.send(task)), watch);
+        (vfs, roots, VfsTaskStream { shared })
+    }
+
+    /// Handles the `tasks` until the initial load of every root has finished.
+    ///
+    /// Returns `false` if the stream ended before that.
+    pub async fn wait_all_roots_loaded(&mut self, tasks: &mut VfsTaskStream) -> bool {
+        while !self.all_roots_loaded() {
+            match (NextTask { tasks: &mut *tasks }).await {
+                Some(task) => self.handle_task(task),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Resolves to the next task of the stream.
+struct NextTask<'a> {
+    tasks: &'a mut VfsTaskStream,
+}
+
+impl Future for NextTask<'_> {
+    type Output = Option<VfsTask>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<VfsTask>> {
+        Pin::new(&mut *self.tasks).poll_next(cx)
+    }
+}
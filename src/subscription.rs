@@ -0,0 +1,222 @@
+//! Independent consumers of the changes of the VFS.
+//!
+//! Every subscriber has a cursor into a shared log of changes, which is
+//! trimmed once all subscribers have moved past its beginning.
+use std::collections::VecDeque;
+
+use relative_path::{RelativePath, RelativePathBuf};
+use rustc_hash::FxHashMap;
+
+use crate::{Vfs, VfsChange, VfsRoot};
+
+/// Identifies a subscriber to the changes, see `Vfs::subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subscription(u32);
+
+/// Selects the changes a subscriber receives. By default, it receives all of
+/// them.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeFilter {
+    roots: Option<Vec<VfsRoot>>,
+    glob: Option<Glob>,
+}
+
+impl ChangeFilter {
+    pub fn all() -> ChangeFilter {
+        ChangeFilter::default()
+    }
+
+    /// Only receive the changes of `roots`.
+    pub fn roots(mut self, roots: impl IntoIterator<Item = VfsRoot>) -> ChangeFilter {
+        self.roots = Some(roots.into_iter().collect());
+        self
+    }
+
+    /// Only receive the changes of files whose paths, relative to their
+    /// roots, match `pattern`.
+    ///
+    /// In the pattern, `?` matches any character and `*` any number of
+    /// characters except `/`, while a `**` component matches any number of
+    /// directories. For example, `**/*.rs` matches all Rust files, and
+    /// `Cargo.toml` only the manifest at the top of a root.
+    pub fn glob(mut self, pattern: &str) -> ChangeFilter {
+        self.glob = Some(Glob(pattern.split('/').map(str::to_string).collect()));
+        self
+    }
+
+    fn includes_root(&self, root: VfsRoot) -> bool {
+        match &self.roots {
+            Some(roots) => roots.contains(&root),
+            None => true,
+        }
+    }
+
+    fn includes_path(&self, path: &RelativePath) -> bool {
+        match &self.glob {
+            Some(glob) => glob.matches(path),
+            None => true,
+        }
+    }
+
+    /// Returns `change` restricted to the files which pass the filter, if any
+    /// of them do. `AddRoot` is always kept, as it marks a loaded root.
+    fn apply(&self, entry: &Entry) -> Option<VfsChange> {
+        if !self.includes_root(entry.root) {
+            return None;
+        }
+        if let Some(path) = &entry.path {
+            return if self.includes_path(path) { Some(entry.change.clone()) } else { None };
+        }
+        let mut change = entry.change.clone();
+        match &mut change {
+            VfsChange::AddRoot { files, unloaded, .. } => {
+                files.retain(|(_, path, _)| self.includes_path(path));
+                unloaded.retain(|(_, path)| self.includes_path(path));
+            }
+            VfsChange::AddRootChunk { files, unloaded, .. } => {
+                files.retain(|(_, path, _)| self.includes_path(path));
+                unloaded.retain(|(_, path)| self.includes_path(path));
+                if files.is_empty() && unloaded.is_empty() {
+                    return None;
+                }
+            }
+            _ => (),
+        }
+        Some(change)
+    }
+}
+
+/// A pattern split into `/`-separated components.
+#[derive(Debug, Clone)]
+struct Glob(Vec<String>);
+
+impl Glob {
+    fn matches(&self, path: &RelativePath) -> bool {
+        let components = path.as_str().split('/').collect::<Vec<_>>();
+        match_components(&self.0, &components)
+    }
+}
+
+fn match_components(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((component, path)) => {
+                match_component(first.as_bytes(), component.as_bytes())
+                    && match_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_component(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| match_component(rest, &text[skip..])),
+        Some((&c, rest)) => match text.split_first() {
+            Some((&t, text)) => (c == b'?' || c == t) && match_component(rest, text),
+            None => false,
+        },
+    }
+}
+
+/// A change, with the root and the path of the file it is about, so that it
+/// can be filtered later, even if the file moves.
+#[derive(Debug)]
+struct Entry {
+    change: VfsChange,
+    root: VfsRoot,
+    /// `None` for the changes which add roots and list their files.
+    path: Option<RelativePathBuf>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    /// Index of the next change of the subscriber in the log.
+    cursor: u64,
+    filter: ChangeFilter,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ChangeLog {
+    entries: VecDeque<Entry>,
+    /// Index of the first entry since the VFS was created.
+    start: u64,
+    subscribers: FxHashMap<Subscription, Subscriber>,
+    next_id: u32,
+}
+
+impl ChangeLog {
+    fn end(&self) -> u64 {
+        self.start + self.entries.len() as u64
+    }
+
+    /// Drops the entries which every subscriber has already received.
+    fn trim(&mut self) {
+        let min_cursor = self.subscribers.values().map(|it| it.cursor).min().unwrap_or(self.end());
+        while self.start < min_cursor {
+            self.entries.pop_front();
+            self.start += 1;
+        }
+    }
+}
+
+impl Vfs {
+    /// Adds a subscriber which receives the changes passing `filter`, starting
+    /// with the next one.
+    ///
+    /// Subscribers drain their changes with `take_changes`, independently of
+    /// each other and of `commit_changes`. Changes are kept until every
+    /// subscriber has taken them, so subscribers which are no longer needed
+    /// should be removed with `unsubscribe`.
+    pub fn subscribe(&mut self, filter: ChangeFilter) -> Subscription {
+        let log = &mut self.change_log;
+        let subscription = Subscription(log.next_id);
+        log.next_id += 1;
+        let cursor = log.end();
+        log.subscribers.insert(subscription, Subscriber { cursor, filter });
+        subscription
+    }
+
+    pub fn unsubscribe(&mut self, subscription: Subscription) {
+        self.change_log.subscribers.remove(&subscription);
+        self.change_log.trim();
+    }
+
+    /// Returns the changes for `subscription` since the last call.
+    pub fn take_changes(&mut self, subscription: Subscription) -> Vec<VfsChange> {
+        let log = &mut self.change_log;
+        let end = log.end();
+        let subscriber = match log.subscribers.get_mut(&subscription) {
+            Some(it) => it,
+            None => return Vec::new(),
+        };
+        let skip = (subscriber.cursor - log.start) as usize;
+        let changes =
+            log.entries.iter().skip(skip).filter_map(|it| subscriber.filter.apply(it)).collect();
+        subscriber.cursor = end;
+        log.trim();
+        changes
+    }
+
+    pub(crate) fn log_change(&mut self, change: &VfsChange) {
+        if self.change_log.subscribers.is_empty() {
+            return;
+        }
+        let (root, path) = match change {
+            VfsChange::AddRoot { root, .. } | VfsChange::AddRootChunk { root, .. } => (*root, None),
+            VfsChange::AddFile { root, path, .. } | VfsChange::RemoveFile { root, path, .. } => {
+                (*root, Some(path.clone()))
+            }
+            VfsChange::ChangeFile { file, .. } => {
+                let data = self.file(*file);
+                (data.root, Some(data.path.clone()))
+            }
+        };
+        self.change_log.entries.push_back(Entry { change: change.clone(), root, path });
+    }
+}
@@ -0,0 +1,263 @@
+//! Helpers for deterministic tests of code which uses the VFS.
+//!
+//! A `FakeWatcher` stands in for the file system watcher: the test tells it
+//! which files were created, written, removed or renamed, and it delivers the
+//! changes to the VFS once the `ManualClock` is advanced past the debounce
+//! delay, like the real watcher would after a while. `Vfs::wait_quiescent`
+//! then waits until the VFS has handled everything, without timeouts.
+//!
+//! ```
+//! use std::{path::PathBuf, sync::Arc};
+//!
+//! use ra_vfs::{MemoryFileSystem, RootEntry, Filter, RelativePath};
+//! use ra_vfs::test_support::{FakeWatcher, ManualClock, process_tasks};
+//!
+//! struct IncludeAll;
+//!
+//! impl Filter for IncludeAll {
+//!     fn include_dir(&self, _: &RelativePath) -> bool { true }
+//!     fn include_file(&self, _: &RelativePath) -> bool { true }
+//! }
+//!
+//! let fs = Arc::new(MemoryFileSystem::new());
+//! let clock = ManualClock::new();
+//! let watcher = FakeWatcher::new(&clock);
+//! let roots = vec![RootEntry::new(PathBuf::from("/ws"), Box::new(IncludeAll))];
+//! let (mut vfs, _, tasks) = ra_vfs::Vfs::with_fake_watcher(roots, fs.clone(), &watcher);
+//! process_tasks(&mut vfs, &tasks, 1);
+//!
+//! fs.write("/ws/lib.rs", "fn main() {}");
+//! watcher.create("/ws/lib.rs");
+//! clock.advance(watcher.delay());
+//! process_tasks(&mut vfs, &tasks, 1);
+//! assert_eq!(vfs.commit_changes().n_added(), 1);
+//! ```
+use std::{
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use crossbeam_channel::{select, unbounded, Receiver};
+use parking_lot::Mutex;
+
+use crate::{
+    io::{self, ChangeKind, ChangeSlot},
+    FileSystem, RootEntry, Vfs, VfsRoot, VfsTask, Watch,
+};
+
+/// A clock which only moves when told to.
+///
+/// Clones share the same time.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+#[derive(Default)]
+struct ClockState {
+    now: Duration,
+    watchers: Vec<Weak<Mutex<WatcherState>>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /// Time elapsed since the clock was created.
+    pub fn now(&self) -> Duration {
+        self.state.lock().now
+    }
+
+    /// Moves the clock forward by `by`, and delivers the changes of the
+    /// watchers whose debounce delay has passed.
+    pub fn advance(&self, by: Duration) {
+        let (now, watchers) = {
+            let mut state = self.state.lock();
+            state.now += by;
+            state.watchers.retain(|it| it.upgrade().is_some());
+            (state.now, state.watchers.iter().filter_map(Weak::upgrade).collect::<Vec<_>>())
+        };
+        for watcher in watchers {
+            watcher.lock().flush(now);
+        }
+    }
+}
+
+/// A file system watcher driven by the test.
+///
+/// Like the real watcher, it debounces the events of a path: an event is
+/// delivered once the clock has advanced by `delay` since the last event of
+/// that path, and the events in between are merged. Renames are reported as
+/// the removal of the old path and the creation of the new one.
+///
+/// Clones share the same pending events.
+#[derive(Clone)]
+pub struct FakeWatcher {
+    clock: ManualClock,
+    state: Arc<Mutex<WatcherState>>,
+}
+
+struct WatcherState {
+    delay: Duration,
+    /// Pending events, with the time of the latest event of each path.
+    pending: Vec<(PathBuf, ChangeKind, Duration)>,
+    changes: Option<ChangeSlot>,
+}
+
+impl FakeWatcher {
+    /// Creates a watcher with the same debounce delay as the real one.
+    pub fn new(clock: &ManualClock) -> FakeWatcher {
+        FakeWatcher::with_delay(clock, io::WATCHER_DELAY)
+    }
+
+    pub fn with_delay(clock: &ManualClock, delay: Duration) -> FakeWatcher {
+        let state =
+            Arc::new(Mutex::new(WatcherState { delay, pending: Vec::new(), changes: None }));
+        clock.state.lock().watchers.push(Arc::downgrade(&state));
+        FakeWatcher { clock: clock.clone(), state }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.state.lock().delay
+    }
+
+    pub fn create(&self, path: impl Into<PathBuf>) {
+        self.push(path.into(), ChangeKind::Create);
+    }
+
+    pub fn write(&self, path: impl Into<PathBuf>) {
+        self.push(path.into(), ChangeKind::Write);
+    }
+
+    pub fn remove(&self, path: impl Into<PathBuf>) {
+        self.push(path.into(), ChangeKind::Remove);
+    }
+
+    pub fn rename(&self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) {
+        self.push(from.into(), ChangeKind::Remove);
+        self.push(to.into(), ChangeKind::Create);
+    }
+
+    /// Number of events which were not delivered yet.
+    pub fn n_pending(&self) -> usize {
+        self.state.lock().pending.len()
+    }
+
+    fn push(&self, path: PathBuf, kind: ChangeKind) {
+        let now = self.clock.now();
+        let mut state = self.state.lock();
+        state.push(path, kind, now);
+        state.flush(now);
+    }
+
+    /// Delivers the events to the `vfs` thread of a VFS from now on.
+    fn attach(&self, changes: ChangeSlot) {
+        let now = self.clock.now();
+        let mut state = self.state.lock();
+        state.changes = Some(changes);
+        state.flush(now);
+    }
+}
+
+impl WatcherState {
+    fn push(&mut self, path: PathBuf, kind: ChangeKind, now: Duration) {
+        let kind = match self.pending.iter().position(|(it, ..)| *it == path) {
+            Some(idx) => {
+                let (_, prev, _) = self.pending.remove(idx);
+                match (prev, kind) {
+                    (ChangeKind::Create, ChangeKind::Write) => ChangeKind::Create,
+                    // The file never existed, as far as the VFS knows.
+                    (ChangeKind::Create, ChangeKind::Remove) => return,
+                    (ChangeKind::Remove, ChangeKind::Create) => ChangeKind::Write,
+                    (_, kind) => kind,
+                }
+            }
+            None => kind,
+        };
+        self.pending.push((path, kind, now));
+    }
+
+    fn flush(&mut self, now: Duration) {
+        let sender = match self.changes.as_ref().and_then(|it| it.lock().clone()) {
+            Some(it) => it,
+            // Not attached, or the VFS is gone: keep the events.
+            None => return,
+        };
+        let delay = self.delay;
+        let (due, pending) =
+            self.pending.drain(..).partition::<Vec<_>, _>(|(_, _, time)| *time + delay <= now);
+        self.pending = pending;
+        for (path, kind, _) in due {
+            let _ = sender.send((path, kind));
+        }
+    }
+}
+
+impl Vfs {
+    /// Like `with_channel`, but the changes come from `watcher` instead of
+    /// the file system watcher.
+    pub fn with_fake_watcher(
+        roots: Vec<RootEntry>,
+        fs: Arc<dyn FileSystem>,
+        watcher: &FakeWatcher,
+    ) -> (Vfs, Vec<VfsRoot>, Receiver<VfsTask>) {
+        let (vfs, roots, tasks) = Vfs::with_channel(roots, fs, Watch(false));
+        if let Some(worker) = &vfs.worker {
+            watcher.attach(worker.changes());
+        }
+        (vfs, roots, tasks)
+    }
+
+    /// Waits until the IO thread has nothing left to do, handling the tasks
+    /// from `tasks` meanwhile, and returns the number of handled tasks,
+    /// progress tasks excluded.
+    ///
+    /// The changes delivered by a `FakeWatcher` before the call are handled,
+    /// and so are the roots which are still loading.
+    pub fn wait_quiescent(&mut self, tasks: &Receiver<VfsTask>) -> usize {
+        let mut n_tasks = 0;
+        loop {
+            let mut n_handled = 0;
+            let mut handle = |vfs: &mut Vfs, task: VfsTask| {
+                if task.progress().is_none() {
+                    n_tasks += 1;
+                }
+                n_handled += 1;
+                vfs.handle_task(task);
+            };
+            if let Some(worker) = &self.worker {
+                let (done_sender, done) = unbounded();
+                worker.send(io::Task::Barrier { done: done_sender });
+                // Keep handling tasks, so that a bounded channel doesn't block
+                // the IO thread.
+                loop {
+                    select! {
+                        recv(done) -> _ => break,
+                        recv(tasks) -> task => match task {
+                            Ok(task) => handle(self, task),
+                            Err(_) => break,
+                        },
+                    }
+                }
+            }
+            while let Ok(task) = tasks.try_recv() {
+                handle(self, task);
+            }
+            // Handling a task may have sent more work to the IO thread.
+            if n_handled == 0 || self.worker.is_none() {
+                return n_tasks;
+            }
+        }
+    }
+}
+
+/// Handles the tasks of `vfs` until it is quiescent.
+///
+/// Panics if the number of handled tasks, progress tasks excluded, is not
+/// `n_tasks`.
+pub fn process_tasks(vfs: &mut Vfs, tasks: &Receiver<VfsTask>, n_tasks: usize) {
+    let n_handled = vfs.wait_quiescent(tasks);
+    assert_eq!(n_handled, n_tasks, "unexpected number of VFS tasks");
+}
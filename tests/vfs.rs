@@ -1,13 +1,28 @@
-use std::{collections::HashSet, fs, time::Duration};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 // use flexi_logger::Logger;
-use crossbeam_channel::{RecvTimeoutError, Receiver, unbounded};
-use ra_vfs::{Vfs, VfsChange, RootEntry, Filter, RelativePath, VfsTask, Watch};
+use crossbeam_channel::{RecvTimeoutError, Receiver, Sender, unbounded};
+use flate2::{write::GzEncoder, Compression};
+use ra_vfs::{
+    Vfs, VfsChange, RootEntry, Filter, RelativePath, VfsTask, Watch, MemoryFileSystem, VfsCache,
+    LineEndings, FileSystem, Metadata, ChangeFilter, ChangeOrigin, Recording, Replay, ChangeSet,
+    test_support::{self, FakeWatcher, ManualClock},
+};
 use tempfile::tempdir;
 
 /// Processes exactly `num_tasks` events waiting in the `vfs` message queue.
 ///
+/// Only the tests of the real watcher use this, as its changes arrive after a
+/// while. The other tests wait with `test_support::process_tasks`.
+///
 /// Panics if there are not exactly that many tasks enqueued for processing.
+/// Progress tasks are processed, but not counted.
 fn process_tasks(vfs: &mut Vfs, task_receiver: &mut Receiver<VfsTask>, num_tasks: u32) {
     process_tasks_in_range(vfs, task_receiver, num_tasks, num_tasks);
 }
@@ -22,15 +37,22 @@ fn process_tasks_in_range(
     min_count: u32,
     max_count: u32,
 ) {
-    for i in 0..max_count {
+    let mut i = 0;
+    while i < max_count {
         let task = match task_receiver.recv_timeout(Duration::from_secs(3)) {
             Err(RecvTimeoutError::Timeout) if i >= min_count => return,
             otherwise => otherwise.unwrap(),
         };
         log::debug!("{:?}", task);
+        if task.progress().is_none() {
+            i += 1;
+        }
+        vfs.handle_task(task);
+    }
+    while let Ok(task) = task_receiver.try_recv() {
+        assert!(task.progress().is_some(), "unexpected task {:?}", task);
         vfs.handle_task(task);
     }
-    assert!(task_receiver.is_empty());
 }
 
 macro_rules! assert_match {
@@ -125,7 +147,7 @@ fn test_vfs_ignore() -> std::io::Result<()> {
                     _ => panic!("unexpected change"),
                 };
                 files.into_iter().map(|(_id, path, text)| {
-                    let text: String = (&*text).clone();
+                    let text: String = (*text).clone();
                     (format!("{}", path), text)
                 })
             })
@@ -209,7 +231,7 @@ fn test_vfs_works() -> std::io::Result<()> {
                     _ => panic!("unexpected change"),
                 };
                 files.into_iter().map(|(_id, path, text)| {
-                    let text: String = (&*text).clone();
+                    let text: String = (*text).clone();
                     (format!("{}", path), text)
                 })
             })
@@ -232,7 +254,7 @@ fn test_vfs_works() -> std::io::Result<()> {
     );
     assert!(vfs.commit_changes().is_empty());
 
-    fs::write(&dir.path().join("a/b/baz.rs"), "quux").unwrap();
+    fs::write(dir.path().join("a/b/baz.rs"), "quux").unwrap();
     process_tasks(&mut vfs, &mut task_receiver, 1);
     assert_match!(
         vfs.commit_changes().as_slice(),
@@ -248,7 +270,7 @@ fn test_vfs_works() -> std::io::Result<()> {
     );
 
     // changing file on disk while overlayed doesn't generate a VfsChange
-    fs::write(&dir.path().join("a/b/baz.rs"), "corge").unwrap();
+    fs::write(dir.path().join("a/b/baz.rs"), "corge").unwrap();
     process_tasks(&mut vfs, &mut task_receiver, 1);
     assert_match!(vfs.commit_changes().as_slice(), []);
 
@@ -281,7 +303,7 @@ fn test_vfs_works() -> std::io::Result<()> {
         assert_eq!(path, "sub1/sub2/new.rs");
     });
 
-    fs::rename(&dir.path().join("a/sub1/sub2/new.rs"), &dir.path().join("a/sub1/sub2/new1.rs"))
+    fs::rename(dir.path().join("a/sub1/sub2/new.rs"), dir.path().join("a/sub1/sub2/new1.rs"))
         .unwrap();
 
     // rust-analyzer#734: For testing purposes, work-around
@@ -317,7 +339,7 @@ fn test_vfs_works() -> std::io::Result<()> {
         ),
     }
 
-    fs::remove_file(&dir.path().join("a/sub1/sub2/new1.rs")).unwrap();
+    fs::remove_file(dir.path().join("a/sub1/sub2/new1.rs")).unwrap();
     process_tasks(&mut vfs, &mut task_receiver, 1);
     assert_match!(
         vfs.commit_changes().as_slice(),
@@ -332,14 +354,14 @@ fn test_vfs_works() -> std::io::Result<()> {
             [VfsChange::AddFile { text, .. }],
             assert_eq!(text.as_str(), "memfile")
         );
-        fs::write(&dir.path().join("a/memfile.rs"), "ignore me").unwrap();
+        fs::write(dir.path().join("a/memfile.rs"), "ignore me").unwrap();
         process_tasks(&mut vfs, &mut task_receiver, 1);
         assert_match!(vfs.commit_changes().as_slice(), []);
     }
 
     // should be ignored
     fs::create_dir_all(dir.path().join("a/target")).unwrap();
-    fs::write(&dir.path().join("a/target/new.rs"), "ignore me").unwrap();
+    fs::write(dir.path().join("a/target/new.rs"), "ignore me").unwrap();
 
     assert_match!(
         task_receiver.recv_timeout(Duration::from_millis(300)), // slightly more than watcher debounce delay
@@ -362,18 +384,1099 @@ fn test_disabled_watch() {
 
     let a_root = dir.path().join("a");
 
-    let (mut task_receiver, cb) = task_chan();
+    let (task_receiver, cb) = task_chan();
     let (mut vfs, _) =
         Vfs::new(vec![RootEntry::new(a_root, IncludeRustFiles::boxed())], cb, Watch(false));
-    process_tasks(&mut vfs, &mut task_receiver, 1);
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
     assert_eq!(vfs.commit_changes().len(), 1);
 
     fs::write(dir.path().join("a/foo.rs"), "goodbye").unwrap();
-    assert_match!(
-        task_receiver.recv_timeout(Duration::from_millis(300)),
-        Err(RecvTimeoutError::Timeout)
-    );
+    assert_eq!(vfs.wait_quiescent(&task_receiver), 0);
     vfs.notify_changed(dir.path().join("a/foo.rs"));
-    process_tasks(&mut vfs, &mut task_receiver, 1);
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
     assert_eq!(vfs.commit_changes().len(), 1);
 }
+
+#[test]
+fn test_memory_file_system() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/a/foo.rs", "hello");
+    fs.write("/a/bar.rs", "world");
+    fs.write("/a/b/baz.rs", "nested hello");
+    fs.write("/a/LICENSE", "extensionless file");
+    fs.write("/a/target/debug/build.rs", "ignore me");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![
+            RootEntry::new("/a".into(), IncludeRustFiles::boxed()),
+            RootEntry::new("/a/b".into(), IncludeRustFiles::boxed()),
+        ],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 2);
+    let files = vfs
+        .commit_changes()
+        .into_iter()
+        .flat_map(|change| match change {
+            VfsChange::AddRoot { files, .. } => files,
+            _ => panic!("unexpected change"),
+        })
+        .map(|(_id, path, text)| (path.to_string(), text.to_string()))
+        .collect::<HashSet<_>>();
+    let expected_files = [("foo.rs", "hello"), ("bar.rs", "world"), ("baz.rs", "nested hello")]
+        .iter()
+        .map(|(path, text)| (path.to_string(), text.to_string()))
+        .collect::<HashSet<_>>();
+    assert_eq!(files, expected_files);
+
+    fs.write("/a/foo.rs", "goodbye");
+    vfs.notify_changed("/a/foo.rs".into());
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "goodbye")
+    );
+
+    // removing an overlay restores the contents of the file system
+    vfs.add_file_overlay(Path::new("/a/b/baz.rs"), "overlay".to_string());
+    vfs.remove_file_overlay(Path::new("/a/b/baz.rs"));
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { .. }, VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "nested hello")
+    );
+}
+
+fn crate_archive(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, text) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, text.as_bytes()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+#[test]
+fn test_archive_root() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    let archive_path = Path::new("/registry/cache/foo-1.0.0.crate");
+    fs.write(
+        archive_path,
+        crate_archive(&[
+            ("foo-1.0.0/Cargo.toml", "[package]"),
+            ("foo-1.0.0/src/lib.rs", "mod bar;"),
+            ("foo-1.0.0/src/bar.rs", "fn bar() {}\r\n"),
+            ("foo-1.0.0/target/gen.rs", "ignore me"),
+        ]),
+    );
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::archive(archive_path.to_path_buf(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let files = vfs
+        .commit_changes()
+        .into_iter()
+        .flat_map(|change| match change {
+            VfsChange::AddRoot { files, .. } => files,
+            _ => panic!("unexpected change"),
+        })
+        .map(|(_id, path, text)| (path.to_string(), text.to_string()))
+        .collect::<HashSet<_>>();
+    let expected_files = [("src/lib.rs", "mod bar;"), ("src/bar.rs", "fn bar() {}\n")]
+        .iter()
+        .map(|(path, text)| (path.to_string(), text.to_string()))
+        .collect::<HashSet<_>>();
+    assert_eq!(files, expected_files);
+
+    let lib_rs = archive_path.join("src/lib.rs");
+    let file = vfs.path2file(&lib_rs).unwrap();
+    assert_eq!(vfs.file2path(file), lib_rs);
+
+    // archives are immutable
+    assert_eq!(vfs.add_file_overlay(&lib_rs, "changed".to_string()), Some(file));
+    vfs.remove_file_overlay(&lib_rs);
+    assert!(vfs.commit_changes().is_empty());
+
+    // replacing the archive reloads the whole root
+    fs.write(
+        archive_path,
+        crate_archive(&[("foo-1.0.0/src/lib.rs", "mod baz;"), ("foo-1.0.0/src/baz.rs", "")]),
+    );
+    vfs.notify_changed(archive_path.to_path_buf());
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let mut changes = vfs
+        .commit_changes()
+        .into_iter()
+        .map(|change| match change {
+            VfsChange::AddFile { path, .. } => format!("add {}", path),
+            VfsChange::ChangeFile { text, .. } => format!("change {}", text),
+            VfsChange::RemoveFile { path, .. } => format!("remove {}", path),
+            change => panic!("unexpected change {:?}", change),
+        })
+        .collect::<Vec<_>>();
+    changes.sort();
+    assert_eq!(changes, ["add src/baz.rs", "change mod baz;", "remove src/bar.rs"]);
+}
+
+#[test]
+fn test_cache() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    let archive_path = Path::new("/registry/cache/foo-1.0.0.crate");
+    fs.write(archive_path, crate_archive(&[("foo-1.0.0/src/lib.rs", "mod bar;\r\n")]));
+    fs.write("/project/src/main.rs", "fn main() {}");
+    let entries = || {
+        vec![
+            RootEntry::archive(archive_path.to_path_buf(), IncludeRustFiles::boxed()),
+            RootEntry::new("/project".into(), IncludeRustFiles::boxed()),
+        ]
+    };
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(entries(), fs.clone(), cb, Watch(false));
+    test_support::process_tasks(&mut vfs, &task_receiver, 2);
+    vfs.commit_changes();
+
+    let dir = tempdir().unwrap();
+    let cache_path = dir.path().join("vfs.cache");
+    vfs.cache().write(&cache_path).unwrap();
+    let cache = VfsCache::read(&cache_path).unwrap();
+    assert_eq!(cache, vfs.cache());
+
+    // a truncated cache is invalid
+    let corrupt_path = dir.path().join("corrupt.cache");
+    let bytes = fs::read(&cache_path).unwrap();
+    fs::write(&corrupt_path, &bytes[..bytes.len() - 1]).unwrap();
+    let err = VfsCache::read(&corrupt_path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "unexpected end of the VFS cache");
+
+    // so is a cache with an out of range modification time
+    let mut bytes = b"RAVFS002".to_vec();
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.push(b'a');
+    // an archive root, with its metadata
+    bytes.push(1);
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.push(1);
+    for &(secs, nanos) in [(u64::MAX, 0), (0, 1_000_000_000), (0, u64::MAX)].iter() {
+        let mut bytes = bytes.clone();
+        bytes.extend_from_slice(&u64::to_le_bytes(secs));
+        bytes.extend_from_slice(&u64::to_le_bytes(nanos));
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        fs::write(&corrupt_path, bytes).unwrap();
+        let err = VfsCache::read(&corrupt_path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid time");
+    }
+
+    // both roots are served from the cache immediately, the files of the
+    // project are loaded on first access
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_cache(entries(), fs.clone(), cache.clone(), cb, Watch(false));
+    let lib_rs = archive_path.join("src/lib.rs");
+    let main_rs = Path::new("/project/src/main.rs");
+    let changes = vfs.commit_changes();
+    match changes.as_slice() {
+        [VfsChange::AddRoot { files, .. }, VfsChange::AddRoot { unloaded, .. }] => {
+            match files.as_slice() {
+                [(_, path, text)] => {
+                    assert_eq!(path, "src/lib.rs");
+                    assert_eq!(text.as_str(), "mod bar;\n");
+                }
+                files => panic!("unexpected files {:?}", files),
+            }
+            assert_match!(unloaded.as_slice(), [(_, path)], assert_eq!(path, "src/main.rs"));
+        }
+        changes => panic!("unexpected changes {:?}", changes),
+    }
+    assert_eq!(vfs.file_line_endings(vfs.path2file(&lib_rs).unwrap()), LineEndings::Dos);
+    let main_file = vfs.path2file(main_rs).unwrap();
+    assert!(vfs.file_hash(main_file).is_some());
+    assert_eq!(vfs.load_text(main_file).unwrap().as_str(), "fn main() {}");
+    // the text is the cached one, so loading it is not a change
+    assert!(vfs.commit_changes().is_empty());
+    test_support::process_tasks(&mut vfs, &task_receiver, 0);
+
+    // a stale archive is reloaded, and only the differences of the archive
+    // and the project are reported
+    fs.write(archive_path, crate_archive(&[("foo-1.0.0/src/lib.rs", "mod baz;")]));
+    fs.write(main_rs, "fn main() { bar() }");
+    fs.write("/project/src/bar.rs", "fn bar() {}");
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_cache(entries(), fs.clone(), cache, cb, Watch(false));
+    vfs.commit_changes();
+    test_support::process_tasks(&mut vfs, &task_receiver, 3);
+    let changes = vfs.commit_changes();
+    assert_eq!(changes.len(), 3);
+    assert!(changes.iter().any(
+        |change| matches!(change, VfsChange::ChangeFile { text, .. } if text.as_str() == "mod baz;")
+    ));
+    assert!(changes.iter().any(|change| matches!(
+        change,
+        VfsChange::ChangeFile { text, .. } if text.as_str() == "fn main() { bar() }"
+    )));
+    assert!(changes
+        .iter()
+        .any(|change| matches!(change, VfsChange::AddFile { path, .. } if path == "src/bar.rs")));
+}
+
+#[test]
+fn test_lazy_root() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/deps/foo/lib.rs", "mod bar;");
+    fs.write("/deps/foo/bar.rs", "fn bar() {}");
+    fs.write("/deps/foo/baz.rs", "fn baz() {}");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::new("/deps".into(), IncludeRustFiles::boxed()).lazy()],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let mut unloaded = match vfs.commit_changes().as_slice() {
+        [VfsChange::AddRoot { files, unloaded, .. }] => {
+            assert!(files.is_empty());
+            unloaded.iter().map(|(_, path)| path.to_string()).collect::<Vec<_>>()
+        }
+        changes => panic!("unexpected changes {:?}", changes),
+    };
+    unloaded.sort();
+    assert_eq!(unloaded, ["foo/bar.rs", "foo/baz.rs", "foo/lib.rs"]);
+
+    // loading on the calling thread
+    let lib_rs = vfs.path2file(Path::new("/deps/foo/lib.rs")).unwrap();
+    assert_eq!(vfs.file_text(lib_rs), None);
+    assert_eq!(vfs.load_text(lib_rs).unwrap().as_str(), "mod bar;");
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "mod bar;")
+    );
+
+    // loading on the IO thread
+    let bar_rs = vfs.path2file(Path::new("/deps/foo/bar.rs")).unwrap();
+    vfs.request_text(bar_rs);
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "fn bar() {}")
+    );
+    assert_eq!(vfs.file_text(bar_rs).unwrap().as_str(), "fn bar() {}");
+
+    // editing an unloaded file reports only the edited text
+    let baz_rs = vfs.path2file(Path::new("/deps/foo/baz.rs")).unwrap();
+    assert_eq!(vfs.file_text(baz_rs), None);
+    vfs.change_file_overlay(Path::new("/deps/foo/baz.rs"), |text| text.push('!'));
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "fn baz() {}!")
+    );
+}
+
+#[test]
+fn test_roots_load_in_parallel() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    for i in 0..4 {
+        fs.write(format!("/r{}/lib.rs", i), "fn lib() {}");
+    }
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, roots) = Vfs::with_file_system(
+        (0..4)
+            .map(|i| RootEntry::new(format!("/r{}", i).into(), IncludeRustFiles::boxed()))
+            .collect(),
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    // A change which may arrive while its root is still loading is reported
+    // after the root.
+    fs.write("/r2/lib.rs", "fn changed() {}");
+    vfs.notify_changed("/r2/lib.rs".into());
+    test_support::process_tasks(&mut vfs, &task_receiver, 5);
+
+    let mut added = Vec::new();
+    for change in vfs.commit_changes() {
+        match change {
+            VfsChange::AddRoot { root, files, .. } => {
+                assert_eq!(files.len(), 1);
+                added.push(root);
+            }
+            VfsChange::ChangeFile { .. } => assert!(added.contains(&roots[2])),
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+    added.sort();
+    assert_eq!(added, roots);
+    let lib_rs = vfs.path2file(Path::new("/r2/lib.rs")).unwrap();
+    assert_eq!(vfs.file_text(lib_rs).unwrap().as_str(), "fn changed() {}");
+}
+
+#[test]
+fn test_progress() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    for i in 0..250 {
+        fs.write(format!("/project/f{}.rs", i), "");
+    }
+
+    let (task_receiver, mut cb) = task_chan();
+    let (progress_sender, progress) = unbounded();
+    let cb = Box::new(move |task: VfsTask| {
+        if let Some(it) = task.progress() {
+            progress_sender.send((it.n_files_discovered, it.n_files_read)).unwrap();
+        }
+        cb(task)
+    });
+    let (mut vfs, roots) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    assert!(!vfs.all_roots_loaded());
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    assert_eq!(progress.try_iter().collect::<Vec<_>>(), [(250, 0), (250, 100), (250, 200)]);
+    assert!(vfs.is_root_loaded(roots[0]));
+
+    let root = vfs.add_root(RootEntry::new("/other".into(), IncludeRustFiles::boxed()));
+    assert!(!vfs.all_roots_loaded() && !vfs.is_root_loaded(root));
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    assert!(vfs.all_roots_loaded());
+}
+
+#[test]
+fn test_chunked_load() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    for i in 0..2500 {
+        fs.write(format!("/project/f{}.rs", i), "");
+    }
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, roots) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    // Opened while the root is loading.
+    vfs.add_file_overlay(Path::new("/project/f1234.rs"), "overlay".to_string());
+    test_support::process_tasks(&mut vfs, &task_receiver, 3);
+
+    let mut changes = vfs.commit_changes().into_iter();
+    assert_match!(changes.next(), Some(VfsChange::AddFile { .. }));
+    let mut sizes = Vec::new();
+    let mut paths = HashSet::new();
+    for change in changes {
+        match change {
+            VfsChange::AddRootChunk { root, files, .. }
+            | VfsChange::AddRoot { root, files, .. } => {
+                assert_eq!(root, roots[0]);
+                sizes.push(files.len());
+                for (_, path, text) in files {
+                    assert_eq!(text.as_str(), if path == "f1234.rs" { "overlay" } else { "" });
+                    assert!(paths.insert(path));
+                }
+            }
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+    // The overlay is skipped in its chunk, and reported with the last one.
+    assert_eq!(sizes, [999, 1000, 501]);
+    assert_eq!(paths.len(), 2500);
+}
+
+/// Blocks the walks of the roots until the `gate` is closed, and reports the
+/// roots as their walks are entered.
+struct GatedFileSystem {
+    inner: MemoryFileSystem,
+    gate: Receiver<()>,
+    entered: Sender<PathBuf>,
+}
+
+impl FileSystem for GatedFileSystem {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)> {
+        let _ = self.entered.send(dir.to_path_buf());
+        let _ = self.gate.recv();
+        self.inner.walk(dir, include)
+    }
+}
+
+#[test]
+fn test_cancel_load() {
+    let (gate_sender, gate) = unbounded();
+    let (entered_sender, entered) = unbounded();
+    let fs =
+        Arc::new(GatedFileSystem { inner: MemoryFileSystem::new(), gate, entered: entered_sender });
+    for i in 0..16 {
+        fs.inner.write(format!("/lib{}/lib.rs", i), "");
+    }
+    let entries = (0..16)
+        .map(|i| RootEntry::new(format!("/lib{}", i).into(), IncludeRustFiles::boxed()))
+        .collect::<Vec<_>>();
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, roots) = Vfs::with_file_system(entries, fs.clone(), cb, Watch(false));
+    // Roots are sorted longest path first, so `roots[0]` is `/lib10`, which
+    // is loaded first, and `roots[15]` is `/lib9`, which waits as long as the
+    // loaders are blocked.
+    while entered.recv().unwrap() != Path::new("/lib10") {}
+    vfs.cancel_load(roots[0]);
+    vfs.cancel_load(roots[15]);
+    // A waiting root is reported as soon as it is cancelled, so both
+    // cancellations are handled once it is. The other roots are blocked, so
+    // the VFS can't be quiescent yet.
+    vfs.handle_task(task_receiver.recv().unwrap());
+    drop(gate_sender);
+    test_support::process_tasks(&mut vfs, &task_receiver, 15);
+    assert!(vfs.all_roots_loaded());
+
+    for change in vfs.commit_changes() {
+        match change {
+            VfsChange::AddRoot { root, files, .. } => {
+                let cancelled = root == roots[0] || root == roots[15];
+                assert_eq!(files.len(), if cancelled { 0 } else { 1 });
+            }
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+}
+
+#[test]
+fn test_load_sync() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "mod foo;");
+    fs.write("/deps/foo/lib.rs", "fn foo() {}");
+
+    let mut vfs = Vfs::load_sync_with_file_system(
+        vec![
+            RootEntry::new("/project".into(), IncludeRustFiles::boxed()),
+            RootEntry::new("/deps".into(), IncludeRustFiles::boxed()),
+        ],
+        fs.clone(),
+    );
+    assert!(vfs.all_roots_loaded());
+    let changes = vfs.commit_changes();
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().all(|change| match change {
+        VfsChange::AddRoot { files, .. } => files.len() == 1,
+        _ => false,
+    }));
+
+    fs.write("/project/lib.rs", "mod bar;");
+    vfs.notify_changed("/project/lib.rs".into());
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "mod bar;")
+    );
+
+    fs.write("/other/lib.rs", "");
+    vfs.add_root(RootEntry::new("/other".into(), IncludeRustFiles::boxed()));
+    assert!(vfs.all_roots_loaded());
+    assert_match!(vfs.commit_changes().as_slice(), [VfsChange::AddRoot { .. }]);
+}
+
+#[test]
+fn test_task_channel() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "");
+
+    let (mut vfs, _, task_receiver) = Vfs::with_channel(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    assert!(vfs.all_roots_loaded());
+}
+
+/// Reports the paths of the files it reads.
+struct ReportingFileSystem {
+    inner: MemoryFileSystem,
+    reads: Sender<PathBuf>,
+}
+
+impl FileSystem for ReportingFileSystem {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        let _ = self.reads.send(path.to_path_buf());
+        self.inner.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+    ) -> Vec<(PathBuf, Metadata)> {
+        self.inner.walk(dir, include)
+    }
+}
+
+#[test]
+fn test_bounded_task_channel() {
+    let (reads_sender, reads) = unbounded();
+    let fs = Arc::new(ReportingFileSystem { inner: MemoryFileSystem::new(), reads: reads_sender });
+    for name in ["f0.rs", "f1.rs", "last.rs"].iter() {
+        fs.inner.write(Path::new("/project").join(name), "");
+    }
+
+    let (mut vfs, _, task_receiver) = Vfs::with_bounded_channel(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        Watch(false),
+        1,
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    vfs.commit_changes();
+    reads.try_iter().for_each(drop);
+
+    // `f1.rs` fills the channel, so the changes of `f0.rs` are coalesced.
+    vfs.notify_changed("/project/f1.rs".into());
+    for i in 0..3 {
+        fs.inner.write("/project/f0.rs", i.to_string());
+        vfs.notify_changed("/project/f0.rs".into());
+    }
+    // Changes are read in order, so once `last.rs` is read, the changes of
+    // `f0.rs` are in the backlog.
+    vfs.notify_changed("/project/last.rs".into());
+    while reads.recv().unwrap() != Path::new("/project/last.rs") {}
+    test_support::process_tasks(&mut vfs, &task_receiver, 3);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "2")
+    );
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn test_task_stream() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "");
+    fs.write("/deps/lib.rs", "");
+
+    let (mut vfs, _, mut tasks) = Vfs::with_stream(
+        vec![
+            RootEntry::new("/project".into(), IncludeRustFiles::boxed()),
+            RootEntry::new("/deps".into(), IncludeRustFiles::boxed()),
+        ],
+        fs,
+        Watch(false),
+    );
+    assert!(futures_executor::block_on(vfs.wait_all_roots_loaded(&mut tasks)));
+    assert_eq!(vfs.commit_changes().len(), 2);
+
+    drop(vfs);
+    assert!(futures_executor::block_on_stream(tasks).next().is_none());
+}
+
+struct IncludeAllFiles;
+
+impl Filter for IncludeAllFiles {
+    fn include_dir(&self, _: &RelativePath) -> bool {
+        true
+    }
+
+    fn include_file(&self, _: &RelativePath) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_subscriptions() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/Cargo.toml", "[package]");
+    fs.write("/project/src/lib.rs", "mod foo;");
+    fs.write("/deps/lib.rs", "");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, roots) = Vfs::with_file_system(
+        vec![
+            RootEntry::new("/project".into(), Box::new(IncludeAllFiles)),
+            RootEntry::new("/deps".into(), Box::new(IncludeAllFiles)),
+        ],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    let all = vfs.subscribe(ChangeFilter::all());
+    let rust = vfs.subscribe(ChangeFilter::all().glob("**/*.rs"));
+    let manifests = vfs.subscribe(ChangeFilter::all().roots(vec![roots[0]]).glob("Cargo.toml"));
+    test_support::process_tasks(&mut vfs, &task_receiver, 2);
+
+    let paths = |changes: Vec<VfsChange>| {
+        let mut res = changes
+            .into_iter()
+            .flat_map(|change| match change {
+                VfsChange::AddRoot { files, .. } => files.into_iter().map(|(_, path, _)| path),
+                change => panic!("unexpected change {:?}", change),
+            })
+            .map(|path| path.to_string())
+            .collect::<Vec<_>>();
+        res.sort();
+        res
+    };
+    assert_eq!(paths(vfs.take_changes(rust)), ["lib.rs", "src/lib.rs"]);
+    assert_eq!(paths(vfs.take_changes(manifests)), ["Cargo.toml"]);
+    assert_eq!(vfs.take_changes(rust).len(), 0);
+
+    // Subscribers drain independently of each other and of `commit_changes`.
+    assert_eq!(vfs.commit_changes().len(), 2);
+    let lib_rs = vfs.path2file(Path::new("/project/src/lib.rs")).unwrap();
+    vfs.change_file_overlay(Path::new("/project/src/lib.rs"), |text| text.push_str("mod bar;"));
+    vfs.add_file_overlay(Path::new("/project/Cargo.toml"), "[workspace]".to_string());
+    assert_eq!(vfs.take_changes(all).len(), 2 + 2);
+    match vfs.take_changes(rust).as_slice() {
+        [VfsChange::ChangeFile { file, .. }] => assert_eq!(*file, lib_rs),
+        changes => panic!("unexpected changes {:?}", changes),
+    }
+    assert_eq!(vfs.take_changes(manifests).len(), 1);
+
+    vfs.unsubscribe(all);
+    assert_eq!(vfs.take_changes(all).len(), 0);
+}
+
+#[test]
+fn test_change_sets() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "mod foo;");
+    fs.write("/project/foo.rs", "");
+    fs.write("/deps/lib.rs", "");
+
+    let mut vfs = Vfs::load_sync_with_file_system(
+        vec![
+            RootEntry::new("/project".into(), IncludeRustFiles::boxed()),
+            RootEntry::new("/deps".into(), IncludeRustFiles::boxed()),
+        ],
+        fs.clone(),
+    );
+    let changes = vfs.commit_changes();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes.revisions(), 0..2);
+    assert_eq!(changes.added_roots().len(), 2);
+    assert_eq!((changes.n_added(), changes.n_removed(), changes.n_changed()), (3, 0, 0));
+
+    let project = vfs.path2root(Path::new("/project")).unwrap();
+    let lib_rs = vfs.path2file(Path::new("/project/lib.rs")).unwrap();
+    let foo_rs = vfs.path2file(Path::new("/project/foo.rs")).unwrap();
+    vfs.add_file_overlay(Path::new("/project/lib.rs"), "mod bar;".to_string());
+    vfs.change_file_overlay(Path::new("/project/lib.rs"), |text| text.push_str("mod baz;"));
+    fs.write("/project/foo.rs", "fn foo() {}");
+    vfs.notify_changed("/project/foo.rs".into());
+    let bar_rs = vfs.add_file_overlay(Path::new("/project/bar.rs"), String::new()).unwrap();
+    vfs.remove_file_overlay(Path::new("/project/bar.rs"));
+
+    let changes = vfs.commit_changes();
+    assert_eq!(changes.len(), 5);
+    assert_eq!(changes.iter().count(), 5);
+    assert_eq!(changes.revisions(), 2..7);
+    assert!(changes.added_roots().is_empty());
+    assert_eq!(changes.roots().collect::<Vec<_>>(), [project]);
+    let group = changes.root(project).unwrap();
+    assert_eq!(group.changed(), [lib_rs, foo_rs]);
+    assert_eq!((group.added(), group.removed()), ([bar_rs].as_slice(), [bar_rs].as_slice()));
+    assert!(changes.touches(lib_rs) && changes.touches(foo_rs));
+
+    let changes = vfs.commit_changes();
+    assert!(changes.is_empty() && !changes.touches(lib_rs));
+    assert_eq!(changes.revisions(), 7..7);
+}
+
+#[test]
+fn test_change_origins() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "mod foo;");
+
+    let start = SystemTime::now();
+    let mut vfs = Vfs::load_sync_with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+    );
+    let origins = |vfs: &mut Vfs| {
+        let changes = vfs.commit_changes();
+        assert!(changes.iter().all(|it| start <= it.time() && it.time() <= SystemTime::now()));
+        changes.iter().map(|it| it.origin()).collect::<Vec<_>>()
+    };
+    assert_eq!(origins(&mut vfs), [ChangeOrigin::Api]);
+
+    let lib_rs = Path::new("/project/lib.rs");
+    vfs.add_file_overlay(lib_rs, "mod bar;".to_string());
+    vfs.change_file_overlay(lib_rs, |text| text.push_str("mod baz;"));
+    assert_eq!(origins(&mut vfs), [ChangeOrigin::Overlay, ChangeOrigin::Overlay]);
+
+    // Removing the overlay restores the disk text.
+    vfs.remove_file_overlay(lib_rs);
+    assert_eq!(origins(&mut vfs), [ChangeOrigin::Reconcile]);
+
+    fs.write("/project/lib.rs", "mod qux;");
+    vfs.notify_changed(lib_rs.into());
+    assert_eq!(origins(&mut vfs), [ChangeOrigin::Notification]);
+
+    // Overlays of files outside of roots are removed with the file.
+    let scratch = Path::new("/tmp/scratch.rs");
+    vfs.add_file_overlay(scratch, "fn main() {}".to_string());
+    vfs.remove_file_overlay(scratch);
+    assert_eq!(origins(&mut vfs), [ChangeOrigin::Overlay; 3]);
+}
+
+#[test]
+fn test_record_and_replay() {
+    let dir = tempdir().unwrap();
+    let recording_path = dir.path().join("vfs.rec");
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "mod foo;");
+    fs.write("/project/foo.rs", "fn foo() {}");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    vfs.start_recording(&recording_path).unwrap();
+    let describe = |change: VfsChange| match change {
+        VfsChange::AddRoot { files, .. } => format!("add root {}", files.len()),
+        VfsChange::AddRootChunk { files, .. } => format!("add chunk {}", files.len()),
+        VfsChange::AddFile { file, path, text, origin, .. } => {
+            format!("add {:?} {} {} {:?}", file, path, text, origin)
+        }
+        VfsChange::RemoveFile { file, path, origin, .. } => {
+            format!("remove {:?} {} {:?}", file, path, origin)
+        }
+        VfsChange::ChangeFile { file, text, origin, .. } => {
+            format!("change {:?} {} {:?}", file, text, origin)
+        }
+        change => panic!("unexpected change {:?}", change),
+    };
+    let lib_rs = Path::new("/project/lib.rs");
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    vfs.add_file_overlay(lib_rs, "mod bar;".to_string());
+    vfs.change_file_overlay(lib_rs, |text| text.push_str("mod baz;"));
+    // The text on disk is read when the overlay is removed.
+    fs.write("/project/lib.rs", "mod qux;");
+    vfs.remove_file_overlay(lib_rs);
+    fs.write("/project/foo.rs", "fn foo() { 92 }");
+    vfs.notify_changed("/project/foo.rs".into());
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let out = vfs.add_virtual_root("/out".into());
+    vfs.add_virtual_file(out, "gen.rs".into(), "gen".to_string());
+    let changes = vfs.commit_changes();
+    // the time of a change noticed on the IO thread is part of its result
+    let notified_at = |changes: &[VfsChange]| {
+        changes.iter().find(|it| it.origin() == ChangeOrigin::Notification).unwrap().time()
+    };
+    let notified = notified_at(changes.as_slice());
+    let recorded = changes.into_iter().map(describe).collect::<Vec<_>>();
+    assert_eq!(recorded.len(), 7);
+    drop(vfs);
+
+    // An incomplete event, as left by a crash, is skipped.
+    let mut bytes = fs::read(&recording_path).unwrap();
+    bytes.extend_from_slice(&[1, 2, 3]);
+    fs::write(&recording_path, bytes).unwrap();
+
+    let recording = Recording::read(&recording_path).unwrap();
+    let mut replay = Replay::new(recording, |_| IncludeRustFiles::boxed());
+    let mut changes = Vec::new();
+    while replay.step().is_some() {
+        changes.extend(replay.vfs().commit_changes());
+    }
+    assert_eq!(notified_at(&changes), notified);
+    let replayed = changes.into_iter().map(describe).collect::<Vec<_>>();
+    assert_eq!(replayed, recorded);
+    let vfs = replay.finish();
+    let lib_rs = vfs.path2file(lib_rs).unwrap();
+    assert_eq!(vfs.file_text(lib_rs).unwrap().as_str(), "mod qux;");
+}
+
+#[test]
+fn test_replay_reads_of_overlay_edits() {
+    let dir = tempdir().unwrap();
+    let recording_path = dir.path().join("vfs.rec");
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/lib.rs", "mod foo;");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    vfs.start_recording(&recording_path).unwrap();
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let lib_rs = Path::new("/project/lib.rs");
+    let file = vfs.path2file(lib_rs).unwrap();
+    // Evict the text, so that both calls below read the file.
+    vfs.set_memory_budget(Some(0));
+    assert_eq!(vfs.load_text(file).unwrap().as_str(), "mod foo;");
+    fs.write("/project/lib.rs", "mod bar;");
+    vfs.change_file_overlay(lib_rs, |text| text.push_str("mod baz;"));
+    let changed_texts = |changes: ChangeSet| {
+        changes
+            .into_iter()
+            .filter_map(|change| match change {
+                VfsChange::ChangeFile { text, .. } => Some(text.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(changed_texts(vfs.commit_changes()), ["mod bar;mod baz;"]);
+    drop(vfs);
+
+    let recording = Recording::read(&recording_path).unwrap();
+    let mut vfs = Replay::new(recording, |_| IncludeRustFiles::boxed()).finish();
+    // The text read by `load_text` is unchanged, so it is not reported.
+    assert_eq!(changed_texts(vfs.commit_changes()), ["mod bar;mod baz;"]);
+    assert_eq!(vfs.file_text(file).unwrap().as_str(), "mod bar;mod baz;");
+}
+
+#[test]
+fn test_memory_budget() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/a.rs", "0123456789");
+    fs.write("/project/b.rs", "0123456789");
+    fs.write("/project/c.rs", "0123456789");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    vfs.set_memory_budget(Some(25));
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    vfs.commit_changes();
+    let file = |name| vfs.path2file(&Path::new("/project").join(name)).unwrap();
+    let (a_rs, b_rs, c_rs) = (file("a.rs"), file("b.rs"), file("c.rs"));
+    // identical texts are stored once, so they fit in the budget
+    let stats = vfs.memory_stats();
+    assert_eq!((stats.text_bytes, stats.unique_text_bytes, stats.evictions), (30, 10, 0));
+
+    // a shared text is only freed once all of its files are evicted
+    vfs.set_memory_budget(Some(9));
+    assert_eq!(vfs.file_text(a_rs), None);
+    let stats = vfs.memory_stats();
+    assert_eq!((stats.unique_text_bytes, stats.loaded_files, stats.evictions), (0, 0, 3));
+
+    // overlays are never evicted
+    vfs.add_file_overlay(Path::new("/project/a.rs"), "overlay".to_string());
+    vfs.commit_changes();
+    assert_eq!(vfs.file_text(a_rs).unwrap().as_str(), "overlay");
+    assert_eq!(vfs.file_text(b_rs), None);
+    assert!(vfs.file_hash(b_rs).is_some());
+
+    // unchanged texts are reloaded silently
+    vfs.set_memory_budget(Some(17));
+    assert_eq!(vfs.load_text(b_rs).unwrap().as_str(), "0123456789");
+    assert!(vfs.commit_changes().is_empty());
+    let stats = vfs.memory_stats();
+    assert_eq!((stats.text_bytes, stats.evictions, stats.reloads), (17, 3, 1));
+
+    // changed texts are reported, evicting the least recently accessed file
+    fs.write("/project/c.rs", "changed");
+    assert_eq!(vfs.load_text(c_rs).unwrap().as_str(), "changed");
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, .. }],
+        assert_eq!(text.as_str(), "changed")
+    );
+    assert_eq!(vfs.file_text(b_rs), None);
+    assert_eq!(vfs.memory_stats().text_bytes, 14);
+
+    // texts of archives are never evicted
+    let archive_path = Path::new("/registry/cache/foo-1.0.0.crate");
+    fs.write(archive_path, crate_archive(&[("foo-1.0.0/src/lib.rs", "mod bar;")]));
+    vfs.add_root(RootEntry::archive(archive_path.to_path_buf(), IncludeRustFiles::boxed()));
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    vfs.set_memory_budget(Some(0));
+    let lib_rs = vfs.path2file(&archive_path.join("src/lib.rs")).unwrap();
+    assert_eq!(vfs.file_text(lib_rs).unwrap().as_str(), "mod bar;");
+    assert_eq!(vfs.file_text(c_rs), None);
+}
+
+#[test]
+fn test_eviction_counts_reads_of_texts() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/project/a.rs", "aaaaaaaaaa");
+    fs.write("/project/b.rs", "bbbbbbbbbb");
+    fs.write("/project/c.rs", "cccccccccc");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![RootEntry::new("/project".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 1);
+    let file = |name| vfs.path2file(&Path::new("/project").join(name)).unwrap();
+    let (a_rs, b_rs, c_rs) = (file("a.rs"), file("b.rs"), file("c.rs"));
+
+    // texts read with `file_text`, directly or through a snapshot, are
+    // recently used
+    let snapshot = vfs.snapshot();
+    vfs.file_text(b_rs);
+    snapshot.file_text(a_rs);
+    vfs.set_memory_budget(Some(20));
+    let snapshot = vfs.snapshot();
+    assert_eq!(snapshot.file_text(c_rs), None);
+    assert!(snapshot.file_text(a_rs).is_some());
+    assert!(snapshot.file_text(b_rs).is_some());
+
+    // `b.rs` was read last
+    vfs.set_memory_budget(Some(10));
+    assert_eq!(vfs.file_text(a_rs), None);
+    assert!(vfs.file_text(b_rs).is_some());
+}
+
+#[test]
+fn test_identical_texts_are_shared() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/registry/foo-1.0.0/src/lib.rs", "pub fn foo() {}");
+    fs.write("/registry/foo-1.0.1/src/lib.rs", "pub fn foo() {}");
+    fs.write("/registry/foo-1.0.1/src/bar.rs", "pub fn bar() {}");
+
+    let (task_receiver, cb) = task_chan();
+    let (mut vfs, _) = Vfs::with_file_system(
+        vec![
+            RootEntry::new("/registry/foo-1.0.0".into(), IncludeRustFiles::boxed()),
+            RootEntry::new("/registry/foo-1.0.1".into(), IncludeRustFiles::boxed()),
+        ],
+        fs.clone(),
+        cb,
+        Watch(false),
+    );
+    test_support::process_tasks(&mut vfs, &task_receiver, 2);
+    let texts = vfs
+        .commit_changes()
+        .into_iter()
+        .flat_map(|change| match change {
+            VfsChange::AddRoot { files, .. } => files,
+            change => panic!("unexpected change {:?}", change),
+        })
+        .filter(|(_, path, _)| path == "src/lib.rs")
+        .map(|(_, _, text)| text)
+        .collect::<Vec<_>>();
+    assert_eq!(texts.len(), 2);
+    assert!(Arc::ptr_eq(&texts[0], &texts[1]));
+    let stats = vfs.memory_stats();
+    assert_eq!((stats.text_bytes, stats.unique_text_bytes), (45, 30));
+
+    // editing one of the copies unshares it
+    vfs.add_file_overlay(Path::new("/registry/foo-1.0.1/src/lib.rs"), "pub fn foo() {} ".into());
+    assert_eq!(vfs.memory_stats().unique_text_bytes, 46);
+}
+
+#[test]
+fn test_fake_watcher() {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write("/a/foo.rs", "hello");
+    let clock = ManualClock::new();
+    let watcher = FakeWatcher::new(&clock);
+    let (mut vfs, _, tasks) = Vfs::with_fake_watcher(
+        vec![RootEntry::new("/a".into(), IncludeRustFiles::boxed())],
+        fs.clone(),
+        &watcher,
+    );
+    test_support::process_tasks(&mut vfs, &tasks, 1);
+    vfs.commit_changes();
+
+    // nothing is delivered before the debounce delay
+    fs.write("/a/foo.rs", "goodbye");
+    watcher.write("/a/foo.rs");
+    clock.advance(watcher.delay() / 2);
+    assert_eq!(vfs.wait_quiescent(&tasks), 0);
+    // another event of the same path restarts the delay
+    watcher.write("/a/foo.rs");
+    clock.advance(watcher.delay() / 2);
+    assert_eq!(vfs.wait_quiescent(&tasks), 0);
+    assert_eq!(watcher.n_pending(), 1);
+    clock.advance(watcher.delay() / 2);
+    test_support::process_tasks(&mut vfs, &tasks, 1);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::ChangeFile { text, origin: ChangeOrigin::Watcher, .. }],
+        assert_eq!(text.as_str(), "goodbye")
+    );
+
+    // a write after a create is merged into the create
+    fs.write("/a/bar.rs", "bar");
+    watcher.create("/a/bar.rs");
+    watcher.write("/a/bar.rs");
+    clock.advance(watcher.delay());
+    test_support::process_tasks(&mut vfs, &tasks, 1);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::AddFile { path, text, .. }],
+        assert_eq!((path.as_str(), text.as_str()), ("bar.rs", "bar"))
+    );
+
+    fs.remove(Path::new("/a/bar.rs"));
+    fs.write("/a/baz.rs", "bar");
+    watcher.rename("/a/bar.rs", "/a/baz.rs");
+    clock.advance(watcher.delay());
+    test_support::process_tasks(&mut vfs, &tasks, 2);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::RemoveFile { path: removed, .. }, VfsChange::AddFile { path: added, .. }],
+        assert_eq!((removed.as_str(), added.as_str()), ("bar.rs", "baz.rs"))
+    );
+
+    // a file which is removed right after it is created is never seen
+    watcher.create("/a/tmp.rs");
+    watcher.remove("/a/tmp.rs");
+    assert_eq!(watcher.n_pending(), 0);
+
+    fs.remove(Path::new("/a/foo.rs"));
+    watcher.remove("/a/foo.rs");
+    clock.advance(watcher.delay());
+    test_support::process_tasks(&mut vfs, &tasks, 1);
+    assert_match!(
+        vfs.commit_changes().as_slice(),
+        [VfsChange::RemoveFile { path, .. }],
+        assert_eq!(path.as_str(), "foo.rs")
+    );
+}